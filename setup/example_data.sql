@@ -0,0 +1,24 @@
+-- Example data for development. Start the server once to create the schema,
+-- then run this script on `home/nim.db`.
+
+-- The password of rolf is "judita", the password of doro is "florian".
+INSERT INTO `user` (id, username, password) VALUES (1, 'rolf', '$rpbkdf2$0$AAAnEA==$Wih697v+F5NJGvnRIldzLw==$Bqx2PYzgR5Dg+wBELKRsmt/HaV9LZXQ4QcYK70HNbsU=$');
+INSERT INTO `user` (id, username, password) VALUES (2, 'doro', '$rpbkdf2$0$AAAnEA==$O/nqIkH/YIm/EzV8CfMIPA==$rN7hmPd3gmanCApEXQtsCd4SqA6+EKAu6HGqyvFJp50=$');
+
+-- rolf and doro are friends
+INSERT INTO `friendship` (user, friend, accepted) VALUES (1, 2, 1);
+
+-- We create three example games
+INSERT INTO `game` (id, description) VALUES (1, 'A shared game');
+INSERT INTO `game` (id, description) VALUES (2, 'Rolf''s game');
+INSERT INTO `game` (id, description) VALUES (3, 'Doro''s game');
+
+-- Both players join game 1, rolf is player 1, doro is player 2
+INSERT INTO `game_member` (user, game, role, ready_state) VALUES (1, 1, 1, 2);
+INSERT INTO `game_member` (user, game, role, ready_state) VALUES (2, 1, 2, 2);
+
+-- Only rolf joins game 2, he is player 1
+INSERT INTO `game_member` (user, game, role, ready_state) VALUES (1, 2, 1, 2);
+
+-- Only doro joins game 3, she is player 1
+INSERT INTO `game_member` (user, game, role, ready_state) VALUES (2, 3, 1, 2);
@@ -0,0 +1,29 @@
+-- The schema as it was created by `setup/install.sql` before migrations
+-- existed. Existing databases already contain these tables, which is why they
+-- are only created if they do not exist. All later changes are migrations.
+
+CREATE TABLE IF NOT EXISTS `user` (
+	`id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`username`	INTEGER NOT NULL UNIQUE,
+	`password`	TEXT
+);
+
+CREATE TABLE IF NOT EXISTS `game` (
+	`id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`description`	TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `game_data` (
+	`id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`game`	INTEGER NOT NULL,
+	`data`	TEXT NOT NULL,
+	FOREIGN KEY(`game`) REFERENCES `game`(`id`)
+);
+
+CREATE TABLE IF NOT EXISTS `game_member` (
+	`user`	INTEGER NOT NULL,
+	`game`	INTEGER NOT NULL,
+	`role`	INTEGER NOT NULL,
+	`accepted`	INTEGER NOT NULL DEFAULT 0,
+	UNIQUE(`user`,`game`)
+);
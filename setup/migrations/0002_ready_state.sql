@@ -0,0 +1,9 @@
+-- Games have a status: 1 = setup, 2 = running and 3 = finished. Existing
+-- games have not started yet.
+ALTER TABLE `game` ADD COLUMN `status` INTEGER NOT NULL DEFAULT 1;
+
+-- The accepted flag of the members is replaced by a ready state: 1 = invited,
+-- 2 = accepted and 3 = ready. SQLite can't drop the flag, it is no longer
+-- used.
+ALTER TABLE `game_member` ADD COLUMN `ready_state` INTEGER NOT NULL DEFAULT 1;
+UPDATE `game_member` SET `ready_state` = CASE WHEN `accepted` THEN 2 ELSE 1 END;
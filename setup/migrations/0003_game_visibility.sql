@@ -0,0 +1,3 @@
+-- The visibility is 1 = public, 2 = members only and 3 = unlisted. Existing
+-- games were only shown to their members.
+ALTER TABLE `game` ADD COLUMN `visibility` INTEGER NOT NULL DEFAULT 2;
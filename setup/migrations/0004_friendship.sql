@@ -0,0 +1,15 @@
+-- A friendship starts as a request from `user` to `friend` and becomes
+-- mutual once it is accepted. There is at most one row for each pair.
+CREATE TABLE `friendship` (
+	`user`	INTEGER NOT NULL,
+	`friend`	INTEGER NOT NULL,
+	`accepted`	INTEGER NOT NULL DEFAULT 0,
+	UNIQUE(`user`,`friend`)
+);
+
+-- A blocked user can not send friend requests to or invite the blocking user.
+CREATE TABLE `user_block` (
+	`user`	INTEGER NOT NULL,
+	`blocked_user`	INTEGER NOT NULL,
+	UNIQUE(`user`,`blocked_user`)
+);
@@ -0,0 +1,57 @@
+-- Usernames are stored as text and the tables that reference users and games
+-- get foreign keys. SQLite can't change columns or add foreign keys to an
+-- existing table, so the tables are rebuilt. Rows that reference a missing
+-- user or game are dropped, and so is the accepted flag of the members that
+-- the ready state replaced.
+
+CREATE TABLE `user_new` (
+	`id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+	`username`	TEXT NOT NULL UNIQUE,
+	`password`	TEXT
+);
+INSERT INTO `user_new` (id, username, password)
+	SELECT id, CAST(username AS TEXT), password FROM `user`;
+DROP TABLE `user`;
+ALTER TABLE `user_new` RENAME TO `user`;
+
+CREATE TABLE `friendship_new` (
+	`user`	INTEGER NOT NULL,
+	`friend`	INTEGER NOT NULL,
+	`accepted`	INTEGER NOT NULL DEFAULT 0,
+	UNIQUE(`user`,`friend`),
+	FOREIGN KEY(`user`) REFERENCES `user`(`id`) ON DELETE CASCADE,
+	FOREIGN KEY(`friend`) REFERENCES `user`(`id`) ON DELETE CASCADE
+);
+INSERT INTO `friendship_new` (user, friend, accepted)
+	SELECT user, friend, accepted FROM `friendship`
+	WHERE user IN (SELECT id FROM `user`) AND friend IN (SELECT id FROM `user`);
+DROP TABLE `friendship`;
+ALTER TABLE `friendship_new` RENAME TO `friendship`;
+
+CREATE TABLE `user_block_new` (
+	`user`	INTEGER NOT NULL,
+	`blocked_user`	INTEGER NOT NULL,
+	UNIQUE(`user`,`blocked_user`),
+	FOREIGN KEY(`user`) REFERENCES `user`(`id`) ON DELETE CASCADE,
+	FOREIGN KEY(`blocked_user`) REFERENCES `user`(`id`) ON DELETE CASCADE
+);
+INSERT INTO `user_block_new` (user, blocked_user)
+	SELECT user, blocked_user FROM `user_block`
+	WHERE user IN (SELECT id FROM `user`) AND blocked_user IN (SELECT id FROM `user`);
+DROP TABLE `user_block`;
+ALTER TABLE `user_block_new` RENAME TO `user_block`;
+
+CREATE TABLE `game_member_new` (
+	`user`	INTEGER NOT NULL,
+	`game`	INTEGER NOT NULL,
+	`role`	INTEGER NOT NULL,
+	`ready_state`	INTEGER NOT NULL DEFAULT 1,
+	UNIQUE(`user`,`game`),
+	FOREIGN KEY(`user`) REFERENCES `user`(`id`) ON DELETE CASCADE,
+	FOREIGN KEY(`game`) REFERENCES `game`(`id`) ON DELETE CASCADE
+);
+INSERT INTO `game_member_new` (user, game, role, ready_state)
+	SELECT user, game, role, ready_state FROM `game_member`
+	WHERE user IN (SELECT id FROM `user`) AND game IN (SELECT id FROM `game`);
+DROP TABLE `game_member`;
+ALTER TABLE `game_member_new` RENAME TO `game_member`;
@@ -0,0 +1,5 @@
+-- Each game stores the rules it was created with as json, see `NimRules`.
+-- Games that already exist were played with the classic rules.
+
+ALTER TABLE `game` ADD COLUMN `rules` TEXT NOT NULL
+	DEFAULT '{"token_count":15,"min_take":1,"max_take":3,"convention":"Normal","contiguous":false,"player_count":2}';
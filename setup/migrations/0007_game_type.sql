@@ -0,0 +1,4 @@
+-- Games can have different types. The type is 1 = Nim or 2 = HeapNim, the
+-- rules column holds the rules of this type. Existing games are Nim games.
+
+ALTER TABLE `game` ADD COLUMN `game_type` INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,214 @@
+//! Command line interface
+//!
+//! Without a command, the binary runs the server. The other commands help
+//! operators to set up the server and to manage users and games, so they
+//! never have to open the database by hand.
+
+use super::config::{self, Config};
+use super::db;
+use super::migration;
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: demo-game-server [--config <path>] [command]
+
+Commands:
+    serve                          Runs the server, this is the default.
+    generate-secret                Writes a new configuration file with a random secret.
+    migrate                        Updates the database schema.
+    user list
+    user create <username>         Reads the password from stdin.
+    user delete <username>
+    user reset-password <username> Reads the password from stdin.
+    game list
+    game show <id>                 Prints the game with its actions and state.
+    game finish <id>
+    game delete <id>";
+
+/// What the binary should do, this is given on the command line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    GenerateSecret,
+    Migrate,
+    ListUsers,
+    CreateUser(String),
+    DeleteUser(String),
+    ResetPassword(String),
+    ListGames,
+    ShowGame(i64),
+    FinishGame(i64),
+    DeleteGame(i64),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Arguments {
+    pub command: Command,
+    pub config_path: PathBuf,
+}
+
+/// Reads the command and the configuration file from the command line.
+pub fn parse_arguments(mut args: impl Iterator<Item = String>) -> Result<Arguments, String> {
+    let mut config_path = PathBuf::from(config::DEFAULT_CONFIG_PATH);
+    let mut words = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            match args.next() {
+                Some(value) => config_path = PathBuf::from(value),
+                None => return Err(format!("The --config flag requires a path.\n{}", USAGE)),
+            }
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            config_path = PathBuf::from(value);
+        } else if arg == "--help" {
+            return Err(USAGE.to_owned());
+        } else {
+            words.push(arg);
+        }
+    }
+
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let command = match words.as_slice() {
+        [] | ["serve"] => Command::Serve,
+        ["generate-secret"] => Command::GenerateSecret,
+        ["migrate"] => Command::Migrate,
+        ["user", "list"] => Command::ListUsers,
+        ["user", "create", username] => Command::CreateUser(username.to_string()),
+        ["user", "delete", username] => Command::DeleteUser(username.to_string()),
+        ["user", "reset-password", username] => Command::ResetPassword(username.to_string()),
+        ["game", "list"] => Command::ListGames,
+        ["game", "show", id] => Command::ShowGame(parse_game_id(id)?),
+        ["game", "finish", id] => Command::FinishGame(parse_game_id(id)?),
+        ["game", "delete", id] => Command::DeleteGame(parse_game_id(id)?),
+        _ => return Err(format!("Unknown command {:?}.\n{}", words.join(" "), USAGE)),
+    };
+
+    Ok(Arguments {
+        command,
+        config_path,
+    })
+}
+
+fn parse_game_id(id: &str) -> Result<i64, String> {
+    id.parse()
+        .map_err(|_| format!("The game id must be a number, not {:?}.", id))
+}
+
+/// Runs one of the administration commands. `Serve` and `GenerateSecret` are
+/// handled by `main`, as they do not work on the database.
+pub fn run(command: Command, config: &Config) -> Result<(), failure::Error> {
+    use Command::*;
+
+    let pool = db::open(&config.database_path)?;
+    // The commands work on the current schema, just like the server.
+    let mut conn = pool.get()?;
+    let applied = migration::migrate(&mut conn)?;
+    if command == Migrate {
+        println!(
+            "Applied {} migrations, the schema version is {}.",
+            applied,
+            migration::schema_version(&conn)?
+        );
+        return Ok(());
+    }
+    drop(conn);
+
+    let iterations = config.security.hashing_iteration_count;
+    match command {
+        Serve | GenerateSecret | Migrate => unreachable!("This command is handled elsewhere."),
+        ListUsers => {
+            for user in db::admin::users(&pool)? {
+                println!("{}\t{}", user.id, user.username);
+            }
+        }
+        CreateUser(username) => {
+            db::admin::create_user(&username, &read_password()?, iterations, &pool)?;
+            println!("Created the user {}.", username);
+        }
+        DeleteUser(username) => {
+            db::admin::delete_user(&username, &pool)?;
+            println!("Deleted the user {}.", username);
+        }
+        ResetPassword(username) => {
+            db::admin::reset_password(&username, &read_password()?, iterations, &pool)?;
+            println!("Changed the password of {}.", username);
+        }
+        ListGames => {
+            for game in db::admin::games(&pool)? {
+                let members: Vec<&str> = game.members.iter().map(|m| m.username.as_str()).collect();
+                println!(
+                    "{}\t{:?}\t{:?}\t{}\t{}",
+                    game.id,
+                    game.status,
+                    game.visibility,
+                    members.join(","),
+                    game.description
+                );
+            }
+        }
+        ShowGame(game_id) => {
+            let game = db::admin::game(game_id, &pool)?;
+            let details = serde_json::json!({
+                "game": game.header,
+                "history": game.history,
+                "state": game.state,
+            });
+            println!("{}", serde_json::to_string_pretty(&details)?);
+        }
+        FinishGame(game_id) => {
+            db::admin::finish_game(game_id, &pool)?;
+            println!("Finished the game {}.", game_id);
+        }
+        DeleteGame(game_id) => {
+            db::admin::delete_game(game_id, &pool)?;
+            println!("Deleted the game {}.", game_id);
+        }
+    }
+    Ok(())
+}
+
+/// Passwords are not passed as arguments, so they do not end up in the
+/// history of the shell.
+fn read_password() -> Result<String, failure::Error> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn read_arguments() {
+        let parse = |args: &[&str]| parse_arguments(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(
+            parse(&[]).unwrap(),
+            Arguments {
+                command: Command::Serve,
+                config_path: PathBuf::from(config::DEFAULT_CONFIG_PATH),
+            }
+        );
+        assert_eq!(
+            parse(&["--config", "a.toml"]).unwrap().config_path,
+            PathBuf::from("a.toml")
+        );
+        assert_eq!(
+            parse(&["generate-secret", "--config=b.toml"]).unwrap(),
+            Arguments {
+                command: Command::GenerateSecret,
+                config_path: PathBuf::from("b.toml"),
+            }
+        );
+        assert_eq!(
+            parse(&["user", "create", "rolf"]).unwrap().command,
+            Command::CreateUser("rolf".to_owned())
+        );
+        assert_eq!(
+            parse(&["game", "finish", "3"]).unwrap().command,
+            Command::FinishGame(3)
+        );
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["game", "show", "three"]).is_err());
+        assert!(parse(&["user", "create"]).is_err());
+    }
+}
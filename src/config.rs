@@ -0,0 +1,489 @@
+//! Configuration
+//!
+//! The configuration is read from a toml file, `./home/config.toml` unless
+//! another file is passed on the command line. Every field can be
+//! overridden by an environment variable, see `FIELDS`. Relative paths are
+//! relative to the working directory of the server.
+//!
+//! The settings are validated when they are loaded, so the server does not
+//! start with an insecure configuration.
+//!
+//! HTTPS is enabled by the optional `[tls]` section. The identity cookie is
+//! only sent over HTTPS in this case, unless `[cookie]` says otherwise.
+
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The configuration file that is used if no `--config` flag is given.
+pub const DEFAULT_CONFIG_PATH: &str = "./home/config.toml";
+
+/// The example configuration, `generate-secret` fills in the secret.
+const CONFIG_TEMPLATE: &str = include_str!("../setup/config.toml");
+const SECRET_PLACEHOLDER: &str = "identity_cookie_secret = \"\"";
+
+/// The cookie policy of actix-identity can't be created with shorter keys.
+const MIN_SECRET_LENGTH: usize = 32;
+const GENERATED_SECRET_LENGTH: usize = 64;
+const MIN_HASHING_ITERATION_COUNT: u32 = 10_000;
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    pub ip: String,
+    pub port: u16,
+    #[serde(default = "default_database_path")]
+    pub database_path: PathBuf,
+    #[serde(default = "default_static_directory")]
+    pub static_directory: PathBuf,
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub game: GameConfig,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub cookie: CookieConfig,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SecurityConfig {
+    pub identity_cookie_secret: String,
+    pub hashing_iteration_count: u32,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct GameConfig {
+    /// Only friends of a member can be invited to a game.
+    pub invite_friends_only: bool,
+}
+
+/// The certificate chain and private key the server uses for HTTPS. Both
+/// files are PEM encoded, the key may be a PKCS#8 or RSA key.
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    pub certificate_path: PathBuf,
+    pub private_key_path: PathBuf,
+}
+
+/// Settings of the identity cookie.
+#[derive(Deserialize, Clone, Default)]
+pub struct CookieConfig {
+    /// Only send the cookie over HTTPS. Defaults to whether `[tls]` is set, a
+    /// proxy that terminates TLS in front of the server needs to set this.
+    pub secure: Option<bool>,
+    #[serde(default)]
+    pub same_site: SameSite,
+    /// Without a maximum age, the cookie is removed when the browser closes.
+    pub max_age_seconds: Option<i64>,
+    /// Without a domain, the cookie is only sent to the host of the server.
+    pub domain: Option<String>,
+}
+
+/// The `SameSite` attribute of the identity cookie.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+fn default_database_path() -> PathBuf {
+    PathBuf::from("./home/nim.db")
+}
+
+fn default_static_directory() -> PathBuf {
+    PathBuf::from("./frontend/static")
+}
+
+/// The kind of value an environment variable is parsed as.
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Integer,
+    Boolean,
+}
+
+/// All fields of the configuration. The environment variable of a field is
+/// its path in upper case with `_` instead of `.` and the prefix `NIM_`, e.g.
+/// `NIM_SECURITY_HASHING_ITERATION_COUNT`. New fields must be added here.
+const FIELDS: &[(&str, Kind)] = &[
+    ("ip", Kind::Text),
+    ("port", Kind::Integer),
+    ("database_path", Kind::Text),
+    ("static_directory", Kind::Text),
+    ("security.identity_cookie_secret", Kind::Text),
+    ("security.hashing_iteration_count", Kind::Integer),
+    ("game.invite_friends_only", Kind::Boolean),
+    ("tls.certificate_path", Kind::Text),
+    ("tls.private_key_path", Kind::Text),
+    ("cookie.secure", Kind::Boolean),
+    ("cookie.same_site", Kind::Text),
+    ("cookie.max_age_seconds", Kind::Integer),
+    ("cookie.domain", Kind::Text),
+];
+
+/// Everything that can go wrong while loading the configuration. The messages
+/// are shown to the operator when the server does not start.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Write(PathBuf, std::io::Error),
+    /// The file is no valid toml or a field is missing or has the wrong type.
+    Parse(PathBuf, toml::de::Error),
+    Environment {
+        variable: String,
+        value: String,
+    },
+    /// Some settings are not secure, each problem is described in a sentence.
+    Insecure(Vec<String>),
+    /// The certificate or the private key for HTTPS can't be used.
+    Tls(PathBuf, String),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ConfigError::*;
+        match self {
+            Read(path, error) => write!(
+                f,
+                "Could not read the configuration file {}: {}\n\
+                 Copy setup/config.toml to this location or pass another file with --config.",
+                path.display(),
+                error
+            ),
+            Parse(path, error) => write!(
+                f,
+                "The configuration file {} is not valid: {}\n\
+                 Compare it to setup/config.toml. Every field can also be set by an \
+                 environment variable, e.g. NIM_PORT.",
+                path.display(),
+                error
+            ),
+            Write(path, error) => write!(
+                f,
+                "Could not write the configuration file {}: {}",
+                path.display(),
+                error
+            ),
+            Environment { variable, value } => write!(
+                f,
+                "The environment variable {} has the invalid value {:?}.",
+                variable, value
+            ),
+            Insecure(problems) => {
+                writeln!(f, "The security settings are not safe to use:")?;
+                for problem in problems {
+                    writeln!(f, "  - {}", problem)?;
+                }
+                write!(
+                    f,
+                    "Compare the settings to setup/config.toml. Run `demo-game-server \
+                     generate-secret --config <new path>` to create a configuration with a \
+                     strong secret."
+                )
+            }
+            Tls(path, problem) => write!(
+                f,
+                "Could not set up HTTPS with {}: {}",
+                path.display(),
+                problem
+            ),
+        }
+    }
+}
+
+impl Config {
+    pub fn server_address(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    /// Reads the configuration file, applies the environment variables and
+    /// validates the result.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut table = read_table(path)?;
+        apply_environment(&mut table, |variable| std::env::var(variable).ok())?;
+
+        let config: Config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|error| ConfigError::Parse(path.to_owned(), error))?;
+        config.security.validate()?;
+        config.validate_cookie()?;
+        Ok(config)
+    }
+
+    /// Whether the identity cookie is only sent over HTTPS.
+    pub fn secure_cookie(&self) -> bool {
+        self.cookie.secure.unwrap_or_else(|| self.tls.is_some())
+    }
+
+    fn validate_cookie(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        // Browsers reject cookies with `SameSite=None` that are not secure.
+        if self.cookie.same_site == SameSite::None && !self.secure_cookie() {
+            problems
+                .push("The cookie can only have same_site = \"None\" if it is secure.".to_owned());
+        }
+        if let Some(max_age) = self.cookie.max_age_seconds {
+            if max_age <= 0 {
+                problems.push(format!(
+                    "The cookie max_age_seconds must be positive, it is {}.",
+                    max_age
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Insecure(problems))
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Reads the certificate chain and the private key.
+    pub fn load(&self) -> Result<rustls::ServerConfig, ConfigError> {
+        use rustls::internal::pemfile;
+
+        let certificates = pemfile::certs(&mut self.open(&self.certificate_path)?)
+            .map_err(|()| self.error(&self.certificate_path, "The file is no PEM file."))?;
+        if certificates.is_empty() {
+            return Err(self.error(&self.certificate_path, "The file contains no certificate."));
+        }
+
+        // PKCS#8 keys are tried first, as they are what most tools create now.
+        let mut keys = pemfile::pkcs8_private_keys(&mut self.open(&self.private_key_path)?)
+            .map_err(|()| self.error(&self.private_key_path, "The file is no PEM file."))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut self.open(&self.private_key_path)?)
+                .map_err(|()| self.error(&self.private_key_path, "The file is no PEM file."))?;
+        }
+        if keys.is_empty() {
+            return Err(self.error(&self.private_key_path, "The file contains no private key."));
+        }
+
+        let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        config
+            .set_single_cert(certificates, keys.remove(0))
+            .map_err(|error| self.error(&self.private_key_path, &error.to_string()))?;
+        Ok(config)
+    }
+
+    fn open(&self, path: &Path) -> Result<std::io::BufReader<std::fs::File>, ConfigError> {
+        let file =
+            std::fs::File::open(path).map_err(|error| self.error(path, &error.to_string()))?;
+        Ok(std::io::BufReader::new(file))
+    }
+
+    fn error(&self, path: &Path, problem: &str) -> ConfigError {
+        ConfigError::Tls(path.to_owned(), problem.to_owned())
+    }
+}
+
+impl SecurityConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.identity_cookie_secret.len() < MIN_SECRET_LENGTH {
+            problems.push(format!(
+                "The identity_cookie_secret must be at least {} characters long, it has {}.",
+                MIN_SECRET_LENGTH,
+                self.identity_cookie_secret.len()
+            ));
+        }
+        if self.hashing_iteration_count < MIN_HASHING_ITERATION_COUNT {
+            problems.push(format!(
+                "The hashing_iteration_count must be at least {}, it is {}.",
+                MIN_HASHING_ITERATION_COUNT, self.hashing_iteration_count
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Insecure(problems))
+        }
+    }
+}
+
+/// Writes a new configuration file with a random cookie secret. Existing
+/// files are never overwritten.
+pub fn generate_config(path: &Path) -> Result<(), ConfigError> {
+    use std::io::Write;
+
+    let contents = CONFIG_TEMPLATE.replace(
+        SECRET_PLACEHOLDER,
+        &format!("identity_cookie_secret = \"{}\"", generate_secret()),
+    );
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|error| ConfigError::Write(path.to_owned(), error))?;
+    file.write_all(contents.as_bytes())
+        .map_err(|error| ConfigError::Write(path.to_owned(), error))
+}
+
+/// A random secret from the random number generator of the operating system.
+fn generate_secret() -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::rngs::OsRng;
+    use rand::Rng;
+
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_SECRET_LENGTH)
+        .collect()
+}
+
+fn read_table(path: &Path) -> Result<toml::value::Table, ConfigError> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|error| ConfigError::Read(path.to_owned(), error))?;
+    toml::from_str(&contents).map_err(|error| ConfigError::Parse(path.to_owned(), error))
+}
+
+/// Replaces the fields of the configuration with the values of the
+/// environment variables that are set.
+fn apply_environment(
+    table: &mut toml::value::Table,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    for &(field, kind) in FIELDS {
+        let variable = format!("NIM_{}", field.replace('.', "_").to_uppercase());
+        let value = match var(&variable) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let parsed = match kind {
+            Kind::Text => Some(toml::Value::String(value.clone())),
+            Kind::Integer => value.parse().ok().map(toml::Value::Integer),
+            Kind::Boolean => value.parse().ok().map(toml::Value::Boolean),
+        };
+        let parsed = parsed.ok_or_else(|| ConfigError::Environment {
+            variable: variable.clone(),
+            value: value.clone(),
+        })?;
+
+        // Sections that are missing in the file are created.
+        let mut section = &mut *table;
+        let mut keys: Vec<&str> = field.split('.').collect();
+        let key = keys.pop().unwrap_or(field);
+        for name in keys {
+            let entry = section
+                .entry(name.to_owned())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+            section = match entry {
+                toml::Value::Table(inner) => inner,
+                _ => {
+                    return Err(ConfigError::Environment {
+                        variable: variable.clone(),
+                        value: value.clone(),
+                    })
+                }
+            };
+        }
+        section.insert(key.to_owned(), parsed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn override_fields_from_environment() {
+        let mut table: toml::value::Table = toml::from_str(
+            "ip = \"127.0.0.1\"\n\
+             port = 8088\n\
+             [security]\n\
+             identity_cookie_secret = \"secret\"\n",
+        )
+        .unwrap();
+
+        let environment = |variable: &str| match variable {
+            "NIM_PORT" => Some("9000".to_owned()),
+            "NIM_SECURITY_HASHING_ITERATION_COUNT" => Some("20000".to_owned()),
+            "NIM_GAME_INVITE_FRIENDS_ONLY" => Some("true".to_owned()),
+            _ => None,
+        };
+        apply_environment(&mut table, environment).unwrap();
+
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.server_address(), "127.0.0.1:9000");
+        assert_eq!(config.security.hashing_iteration_count, 20000);
+        assert!(config.game.invite_friends_only);
+        assert_eq!(config.database_path, default_database_path());
+        assert!(!config.secure_cookie());
+    }
+
+    #[test]
+    fn derive_cookie_settings() {
+        let mut table: toml::value::Table = toml::from_str(
+            "ip = \"127.0.0.1\"\n\
+             port = 443\n\
+             [security]\n\
+             identity_cookie_secret = \"secret\"\n\
+             hashing_iteration_count = 10000\n",
+        )
+        .unwrap();
+
+        let environment = |variable: &str| match variable {
+            "NIM_TLS_CERTIFICATE_PATH" => Some("cert.pem".to_owned()),
+            "NIM_TLS_PRIVATE_KEY_PATH" => Some("key.pem".to_owned()),
+            "NIM_COOKIE_SAME_SITE" => Some("None".to_owned()),
+            _ => None,
+        };
+        apply_environment(&mut table, environment).unwrap();
+
+        let mut config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert!(config.secure_cookie());
+        assert_eq!(config.cookie.same_site, SameSite::None);
+        assert!(config.validate_cookie().is_ok());
+
+        config.cookie.secure = Some(false);
+        assert!(config.validate_cookie().is_err());
+    }
+
+    #[test]
+    fn reject_invalid_environment() {
+        let mut table = toml::value::Table::new();
+        let result = apply_environment(&mut table, |variable| {
+            if variable == "NIM_PORT" {
+                Some("eighty".to_owned())
+            } else {
+                None
+            }
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn generate_valid_config() {
+        assert!(CONFIG_TEMPLATE.contains(SECRET_PLACEHOLDER));
+
+        let path = std::env::temp_dir().join(format!("config-{}.toml", generate_secret()));
+        generate_config(&path).unwrap();
+        assert!(generate_config(&path).is_err());
+        let config = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(
+            config.security.identity_cookie_secret.len(),
+            GENERATED_SECRET_LENGTH
+        );
+    }
+
+    #[test]
+    fn reject_insecure_settings() {
+        let security = SecurityConfig {
+            identity_cookie_secret: "vaiNg0ae".to_owned(),
+            hashing_iteration_count: 100,
+        };
+        match security.validate() {
+            Err(ConfigError::Insecure(problems)) => assert_eq!(problems.len(), 2),
+            _ => panic!("The settings should be rejected."),
+        }
+    }
+}
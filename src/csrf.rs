@@ -0,0 +1,209 @@
+//! Protection against cross-site request forgery
+//!
+//! Browsers send the identity cookie with every request to the server, even
+//! when another website triggers the request. Requests that change something
+//! therefore have to prove that they come from our client: the server stores
+//! a random token in the `csrf-token` cookie and passes the same token to the
+//! client in the flags of the index page. The client returns it in the
+//! `X-CSRF-Token` header, which other websites can't set, as they can't read
+//! the token.
+
+use super::config::Config;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{header, Method};
+use actix_web::{HttpMessage, HttpRequest};
+
+pub const COOKIE_NAME: &str = "csrf-token";
+pub const HEADER_NAME: &str = "X-CSRF-Token";
+const TOKEN_LENGTH: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CsrfError {
+    /// The request has no token cookie or no token header.
+    MissingToken,
+    TokenMismatch,
+    /// A websocket handshake was started by a page of another website.
+    OriginMismatch,
+}
+
+impl std::fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CsrfError::MissingToken => write!(f, "The request has no CSRF token."),
+            CsrfError::TokenMismatch => write!(f, "The CSRF token does not match the cookie."),
+            CsrfError::OriginMismatch => write!(f, "The origin does not match the host."),
+        }
+    }
+}
+
+/// The token cookie is sent under the same conditions as the identity cookie.
+#[derive(Clone)]
+pub struct TokenCookie {
+    secure: bool,
+    max_age: Option<i64>,
+    domain: Option<String>,
+}
+
+impl TokenCookie {
+    pub fn new(config: &Config) -> Self {
+        TokenCookie {
+            secure: config.secure_cookie(),
+            max_age: config.cookie.max_age_seconds,
+            domain: config.cookie.domain.clone(),
+        }
+    }
+
+    /// Returns the token of the request. If it has none, a new token is
+    /// created and returned with the cookie that stores it.
+    pub fn token(&self, req: &HttpRequest) -> (String, Option<Cookie<'static>>) {
+        if let Some(cookie) = req.cookie(COOKIE_NAME) {
+            if cookie.value().len() == TOKEN_LENGTH {
+                return (cookie.value().to_owned(), None);
+            }
+        }
+
+        let token = generate_token();
+        let mut cookie = Cookie::build(COOKIE_NAME, token.clone())
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Strict)
+            .finish();
+        if let Some(max_age) = self.max_age {
+            cookie.set_max_age(chrono::Duration::seconds(max_age));
+        }
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        (token, Some(cookie))
+    }
+}
+
+/// Checks that a request which may change something carries the token of its
+/// cookie in the header. Safe methods are always allowed.
+pub fn verify(req: &ServiceRequest) -> Result<(), CsrfError> {
+    if is_safe(req.method()) {
+        return Ok(());
+    }
+
+    let cookie = req.cookie(COOKIE_NAME).ok_or(CsrfError::MissingToken)?;
+    let header = req
+        .headers()
+        .get(HEADER_NAME)
+        .ok_or(CsrfError::MissingToken)?;
+
+    if constant_time_eq(cookie.value().as_bytes(), header.as_bytes()) {
+        Ok(())
+    } else {
+        Err(CsrfError::TokenMismatch)
+    }
+}
+
+/// Websocket handshakes are GET requests and can't carry the token header,
+/// but browsers always send the `Origin` of the page that opens the socket.
+/// It must be the host of the server. Clients that are not browsers may omit
+/// the header, they don't send the identity cookie of a user on their own.
+pub fn verify_origin(req: &HttpRequest) -> Result<(), CsrfError> {
+    let origin = match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin.to_str().map_err(|_| CsrfError::OriginMismatch)?,
+        None => return Ok(()),
+    };
+    let (_scheme, origin_host) = origin.split_once("://").ok_or(CsrfError::OriginMismatch)?;
+
+    if origin_host.eq_ignore_ascii_case(req.connection_info().host()) {
+        Ok(())
+    } else {
+        Err(CsrfError::OriginMismatch)
+    }
+}
+
+fn is_safe(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD || method == Method::OPTIONS
+}
+
+/// Compares the tokens without revealing how many bytes match.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn generate_token() -> String {
+    use rand::distributions::Alphanumeric;
+    use rand::rngs::OsRng;
+    use rand::Rng;
+
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    #[test]
+    fn verify_token_header() {
+        let token = generate_token();
+        let post = || {
+            TestRequest::post()
+                .uri("/api/game/create")
+                .cookie(Cookie::new(COOKIE_NAME, token.clone()))
+        };
+
+        assert_eq!(verify(&TestRequest::get().to_srv_request()), Ok(()));
+        assert_eq!(
+            verify(&post().header(HEADER_NAME, token.clone()).to_srv_request()),
+            Ok(())
+        );
+        assert_eq!(
+            verify(&post().to_srv_request()),
+            Err(CsrfError::MissingToken)
+        );
+        assert_eq!(
+            verify(
+                &post()
+                    .header(HEADER_NAME, generate_token())
+                    .to_srv_request()
+            ),
+            Err(CsrfError::TokenMismatch)
+        );
+        assert_eq!(
+            verify(
+                &TestRequest::post()
+                    .header(HEADER_NAME, token.clone())
+                    .to_srv_request()
+            ),
+            Err(CsrfError::MissingToken)
+        );
+    }
+
+    #[test]
+    fn verify_socket_origin() {
+        let socket = |origin: &str| {
+            TestRequest::get()
+                .uri("/api/socket")
+                .header(header::HOST, "nim.example.com:8080")
+                .header(header::ORIGIN, origin)
+                .to_http_request()
+        };
+
+        assert_eq!(
+            verify_origin(&socket("https://nim.example.com:8080")),
+            Ok(())
+        );
+        assert_eq!(
+            verify_origin(&socket("https://evil.example.com")),
+            Err(CsrfError::OriginMismatch)
+        );
+        assert_eq!(
+            verify_origin(&socket("https://nim.example.com")),
+            Err(CsrfError::OriginMismatch)
+        );
+        assert_eq!(
+            verify_origin(&socket("null")),
+            Err(CsrfError::OriginMismatch)
+        );
+        assert_eq!(verify_origin(&TestRequest::get().to_http_request()), Ok(()));
+    }
+}
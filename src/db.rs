@@ -1,77 +1,469 @@
+//! Database module
+//!
+//! As a takeaway of the talk "Immutable Relational Data" by Richard Feldman
+//! I decided to include no id values in any of the structs describing data.
+//! Instead, the ids need to be managed separately.
+//!
+//! https://www.youtube.com/watch?v=28OdemxhfbU
+
 use super::dto;
+use super::game::{self, Game};
+use super::nim::RulesError;
+use super::variant::{ActionError, GameAction, GameRules, GameState};
+use actix_web::error::BlockingError;
 use actix_web::web;
-use failure::Error;
 use futures::Future;
-use r2d2;
-use r2d2_sqlite;
 use rusqlite::params;
 
-/// Database module
-///
-/// As a takeaway of the talk "Immutable Relational Data" by Richard Feldman
-/// I decided to include no id values in any of the structs describing data.
-/// Instead, the ids need to be managed separately.
-///
-/// https://www.youtube.com/watch?v=28OdemxhfbU
+pub mod admin;
 
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
-pub type Connection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
+/// Pooled connections and transactions both dereference to this type.
+pub type Connection = rusqlite::Connection;
+
+/// Opens the database file, it is created if it does not exist.
+pub fn open(path: &std::path::Path) -> Result<Pool, r2d2::Error> {
+    // SQLite only checks foreign keys if this is enabled for each connection.
+    let manager = r2d2_sqlite::SqliteConnectionManager::file(path)
+        .with_init(|conn| conn.execute_batch("PRAGMA foreign_keys = ON;"));
+    Pool::new(manager)
+}
+
+/// Everything that can go wrong in the database module. Apart from `Internal`,
+/// these are caused by the request and reported back to the client.
+#[derive(Debug)]
+pub enum DbError {
+    UnknownUser,
+    GameNotFound,
+    NotAMember,
+    /// Only members who accepted a player seat can play or declare themself
+    /// ready.
+    NotAPlayer,
+    NotInvited,
+    /// The seats can only be changed before the game starts.
+    GameAlreadyStarted,
+    /// Actions are only possible after all players are ready.
+    GameNotRunning,
+    /// Games are only analyzed once they are over, so players can't use the
+    /// analysis during the game.
+    GameNotFinished,
+    NotYourTurn,
+    /// See `validate_username`.
+    InvalidUsername,
+    /// See `validate_password`.
+    InvalidPassword,
+    UsernameTaken,
+    WrongPassword,
+    /// Friendship requires two different users.
+    FriendIsYourself,
+    NoFriendRequest,
+    /// Only friends can be invited, if the server is configured this way.
+    NotAFriend,
+    /// One of the users blocked the other.
+    Blocked,
+    /// The action was rejected by the rules of the game.
+    IllegalAction(ActionError),
+    /// A game can't be created with these rules.
+    InvalidRules(RulesError),
+    /// Errors that are not caused by the request, e.g. a database failure.
+    Internal(failure::Error),
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use DbError::*;
+        match self {
+            UnknownUser => write!(f, "The user does not exist."),
+            GameNotFound => write!(f, "The game does not exist."),
+            NotAMember => write!(f, "The user is not a member of the game."),
+            NotAPlayer => write!(f, "The user does not hold a player seat in the game."),
+            NotInvited => write!(f, "The user has no pending invitation to the game."),
+            GameAlreadyStarted => write!(f, "The game has already started."),
+            GameNotRunning => write!(f, "The game is not running."),
+            GameNotFinished => write!(f, "The game is not finished."),
+            NotYourTurn => write!(f, "It is not the turn of the user."),
+            InvalidUsername => write!(f, "The username does not follow the rules."),
+            InvalidPassword => write!(f, "The password does not follow the rules."),
+            UsernameTaken => write!(f, "The username is already taken."),
+            WrongPassword => write!(f, "The password is not correct."),
+            FriendIsYourself => write!(f, "Users can't be friends with themself."),
+            NoFriendRequest => write!(f, "There is no pending friend request."),
+            NotAFriend => write!(f, "Only friends can be invited."),
+            Blocked => write!(f, "One of the users blocked the other."),
+            IllegalAction(error) => write!(f, "The rules do not allow the action: {:?}", error),
+            InvalidRules(error) => write!(f, "The rules can't be played: {:?}", error),
+            Internal(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(error: rusqlite::Error) -> Self {
+        DbError::Internal(error.into())
+    }
+}
+
+impl From<r2d2::Error> for DbError {
+    fn from(error: r2d2::Error) -> Self {
+        DbError::Internal(error.into())
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(error: serde_json::Error) -> Self {
+        DbError::Internal(error.into())
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(error: std::io::Error) -> Self {
+        DbError::Internal(error.into())
+    }
+}
+
+impl From<failure::Error> for DbError {
+    fn from(error: failure::Error) -> Self {
+        DbError::Internal(error)
+    }
+}
+
+impl DbError {
+    /// True if SQLite reported that another connection holds a lock.
+    fn is_busy(&self) -> bool {
+        match self {
+            DbError::Internal(error) => match error.downcast_ref::<rusqlite::Error>() {
+                Some(rusqlite::Error::SqliteFailure(error, _)) => {
+                    error.code == rusqlite::ErrorCode::DatabaseBusy
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// All database access runs on the thread pool of `web::block`.
+impl From<BlockingError<DbError>> for DbError {
+    fn from(error: BlockingError<DbError>) -> Self {
+        match error {
+            BlockingError::Error(error) => error,
+            BlockingError::Canceled => {
+                DbError::Internal(failure::err_msg("The database operation was canceled."))
+            }
+        }
+    }
+}
+
+/// How often a transaction is attempted before a busy database is reported as
+/// an internal error.
+const TRANSACTION_ATTEMPTS: u64 = 5;
+
+/// Runs a mutation inside a transaction and commits it if the mutation
+/// succeeds, otherwise all changes are rolled back. The transaction takes
+/// the write lock when it begins, so concurrent requests can't interleave
+/// their reads and writes. If the database is busy, the whole mutation is
+/// retried after a short pause.
+fn in_transaction<T>(
+    pool: &Pool,
+    mutation: impl Fn(&Connection) -> Result<T, DbError>,
+) -> Result<T, DbError> {
+    use rusqlite::TransactionBehavior::Immediate;
+
+    let mut conn = pool.get()?;
+    let mut attempt = 1;
+    loop {
+        let result = conn
+            .transaction_with_behavior(Immediate)
+            .map_err(DbError::from)
+            .and_then(|transaction| {
+                let value = mutation(&transaction)?;
+                transaction.commit()?;
+                Ok(value)
+            });
+
+        match result {
+            Err(ref error) if error.is_busy() && attempt < TRANSACTION_ATTEMPTS => {
+                std::thread::sleep(std::time::Duration::from_millis(10 * attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
 
+/// Checks the password of a user. Hashes that were created with less than the
+/// configured number of iterations are replaced after a successful login.
+/// The slow hashing runs outside of a transaction, so it does not hold the
+/// write lock, which would block everyone else.
 pub fn check_password(
     username: String,
     password: String,
+    hashing_iteration_count: u32,
     pool: &Pool,
-) -> impl Future<Item = bool, Error = actix_web::Error> {
+) -> impl Future<Item = bool, Error = DbError> {
     let pool = pool.clone();
-    web::block(move || check_password_(&username, &password, pool.get()?)).from_err()
+    web::block(move || {
+        let password_hash = password_hash_(&username, &*pool.get()?)?;
+        if !verify_password(&password, password_hash.as_deref(), hashing_iteration_count)? {
+            return Ok(false);
+        }
+
+        let password_hash = password_hash.unwrap_or_default();
+        if hash_iteration_count(&password_hash) < Some(hashing_iteration_count) {
+            let new_hash = pbkdf2::pbkdf2_simple(&password, hashing_iteration_count)?;
+            // If the password was changed in the meantime, the new one stays.
+            in_transaction(&pool, |conn| {
+                replace_password_hash_(&username, &password_hash, &new_hash, conn)
+            })?;
+        }
+        Ok(true)
+    })
+    .from_err()
+}
+
+/// A valid hash that is checked when the user does not exist, so the response
+/// time does not reveal which usernames are taken. Logins upgrade the hashes
+/// of real users to the configured iteration count, so the dummy hash uses
+/// the same count. It is created once for each count.
+fn dummy_password_hash(hashing_iteration_count: u32) -> Result<String, DbError> {
+    use std::sync::Mutex;
+    static HASHES: Mutex<Vec<(u32, String)>> = Mutex::new(Vec::new());
+
+    let known = HASHES
+        .lock()
+        .map_err(|_| failure::err_msg("The dummy password hashes are poisoned."))?
+        .iter()
+        .find(|(count, _)| *count == hashing_iteration_count)
+        .map(|(_, hash)| hash.clone());
+    if let Some(hash) = known {
+        return Ok(hash);
+    }
+
+    let hash = pbkdf2::pbkdf2_simple("not the password of anyone", hashing_iteration_count)?;
+    HASHES
+        .lock()
+        .map_err(|_| failure::err_msg("The dummy password hashes are poisoned."))?
+        .push((hashing_iteration_count, hash.clone()));
+    Ok(hash)
 }
 
-fn check_password_(username: &str, password: &str, conn: Connection) -> Result<bool, Error> {
+/// Checks the password against the stored hash of a user, which is `None` if
+/// the user does not exist or has no password.
+fn verify_password(
+    password: &str,
+    password_hash: Option<&str>,
+    hashing_iteration_count: u32,
+) -> Result<bool, DbError> {
     use pbkdf2::pbkdf2_check;
+
+    match password_hash {
+        Some(password_hash) => Ok(pbkdf2_check(password, password_hash).is_ok()),
+        None => {
+            // Unknown users and users without a password can't log in, but
+            // we still do the same amount of work.
+            let _ = pbkdf2_check(password, &dummy_password_hash(hashing_iteration_count)?);
+            Ok(false)
+        }
+    }
+}
+
+fn password_hash_(username: &str, conn: &Connection) -> Result<Option<String>, DbError> {
     // TODO: Use a prepared statement
     let stmt = "SELECT password FROM user WHERE username = :username";
 
-    let mut prep_stmt = conn.prepare(&stmt)?;
-    let password_hash: String = prep_stmt
+    let mut prep_stmt = conn.prepare(stmt)?;
+    let password_hash = prep_stmt
         .query_map_named(&[(":username", &username)], |row| row.get(0))?
-        .nth(0)
-        .unwrap()?;
+        .next();
+
+    match password_hash {
+        Some(row) => Ok(row?),
+        None => Ok(None),
+    }
+}
+
+/// Reads the iteration count from a hash in the format of `pbkdf2_simple`:
+/// `$rpbkdf2$0$<base64(count)>$<base64(salt)>$<base64(hash)>$`
+fn hash_iteration_count(password_hash: &str) -> Option<u32> {
+    let count = base64::decode(password_hash.split('$').nth(3)?).ok()?;
+    if count.len() == 4 {
+        Some(u32::from_be_bytes([count[0], count[1], count[2], count[3]]))
+    } else {
+        None
+    }
+}
+
+/// Validates a new password and hashes it. This is slow, so it happens before
+/// a transaction takes the write lock.
+fn hash_new_password(password: &str, hashing_iteration_count: u32) -> Result<String, DbError> {
+    validate_password(password)?;
+    Ok(pbkdf2::pbkdf2_simple(password, hashing_iteration_count)?)
+}
 
-    Ok(pbkdf2_check(password, &password_hash).is_ok())
+fn set_password_hash_(
+    username: &str,
+    password_hash: &str,
+    conn: &Connection,
+) -> Result<(), DbError> {
+    conn.execute(
+        "update user set password = ?1 where username = ?2",
+        params![password_hash, username],
+    )?;
+    Ok(())
+}
+
+/// Replaces the hash only if it is still the one that was checked. Returns
+/// false if the password was changed in between.
+fn replace_password_hash_(
+    username: &str,
+    old_hash: &str,
+    new_hash: &str,
+    conn: &Connection,
+) -> Result<bool, DbError> {
+    let updated = conn.execute(
+        "update user set password = ?1 where username = ?2 and password = ?3",
+        params![new_hash, username, old_hash],
+    )?;
+    Ok(updated == 1)
+}
+
+/// Usernames are 3 to 20 characters long and may only contain letters, digits,
+/// "-" and "_".
+fn validate_username(username: &str) -> Result<(), DbError> {
+    let username_is_valid = (3..=20).contains(&username.chars().count())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if username_is_valid {
+        Ok(())
+    } else {
+        Err(DbError::InvalidUsername)
+    }
+}
+
+/// Passwords are 8 to 256 characters long.
+fn validate_password(password: &str) -> Result<(), DbError> {
+    if (8..=256).contains(&password.chars().count()) {
+        Ok(())
+    } else {
+        Err(DbError::InvalidPassword)
+    }
+}
+
+pub fn register_user(
+    username: String,
+    password: String,
+    hashing_iteration_count: u32,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        validate_username(&username)?;
+        let password_hash = hash_new_password(&password, hashing_iteration_count)?;
+        in_transaction(&pool, |conn| {
+            register_user_(&username, &password_hash, conn)
+        })
+    })
+    .from_err()
+}
+
+/// Inserts a user whose username was validated and whose password is hashed.
+fn register_user_(username: &str, password_hash: &str, conn: &Connection) -> Result<(), DbError> {
+    let mut stmt = conn.prepare("select 1 from user where username = ?1")?;
+    if stmt.exists(params![username])? {
+        return Err(DbError::UsernameTaken);
+    }
+
+    conn.execute(
+        "insert into user (username, password) values (?1, ?2)",
+        params![username, password_hash],
+    )?;
+
+    Ok(())
+}
+
+/// The user needs to know the old password, a stolen session is not enough
+/// to take over the account.
+pub fn change_password(
+    username: String,
+    old_password: String,
+    new_password: String,
+    hashing_iteration_count: u32,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        let old_hash = password_hash_(&username, &*pool.get()?)?;
+        if !verify_password(&old_password, old_hash.as_deref(), hashing_iteration_count)? {
+            return Err(DbError::WrongPassword);
+        }
+        let new_hash = hash_new_password(&new_password, hashing_iteration_count)?;
+
+        let old_hash = old_hash.unwrap_or_default();
+        in_transaction(&pool, |conn| {
+            if replace_password_hash_(&username, &old_hash, &new_hash, conn)? {
+                Ok(())
+            } else {
+                // Someone else changed the password since it was checked.
+                Err(DbError::WrongPassword)
+            }
+        })
+    })
+    .from_err()
 }
 
 pub fn create_game(
     username: String,
     game: dto::GameCreate,
     pool: &Pool,
-) -> impl Future<Item = dto::GameHeader, Error = actix_web::Error> {
+) -> impl Future<Item = dto::GameHeader, Error = DbError> {
     let pool = pool.clone();
-    web::block(move || create_game_(username, game, &pool.get()?)).from_err()
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            create_game_(username.clone(), game.clone(), conn)
+        })
+    })
+    .from_err()
 }
 
 fn create_game_(
     username: String,
     game: dto::GameCreate,
     conn: &Connection,
-) -> Result<dto::GameHeader, Error> {
+) -> Result<dto::GameHeader, DbError> {
+    game.rules.validate().map_err(DbError::InvalidRules)?;
+    if game.rules.player_count() > dto::MemberRole::PLAYER_SEATS {
+        return Err(DbError::InvalidRules(RulesError::PlayerCount));
+    }
+
+    let user_id = user_id_(username, conn)?;
     conn.execute(
-        "INSERT INTO game (description) VALUES (?1)",
-        params![game.description],
+        "INSERT INTO game (description, visibility, game_type, rules) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            game.description,
+            game.visibility,
+            game.rules.game_type(),
+            game.rules.to_json()?
+        ],
     )?;
     let game_id = conn.last_insert_rowid();
 
-    let default_role = 1;
+    let default_role = dto::MemberRole::WhitePlayer;
 
     // The user automatically accepts their own game invite.
     conn.execute(
-        "INSERT INTO game_member (user, game, role, accepted) VALUES \
-         ((select id from user where username = ?1), ?2, ?3, 1)",
-        params![username, game_id, default_role],
+        "INSERT INTO game_member (user, game, role, ready_state) VALUES (?1, ?2, ?3, ?4)",
+        params![user_id, game_id, default_role, dto::ReadyState::Accepted],
     )?;
 
     Ok(dto::GameHeader {
         id: game_id,
         description: game.description,
+        status: dto::GameStatus::Setup,
+        visibility: game.visibility,
+        rules: game.rules,
         members: members_by_game_(game_id, conn)?,
     })
 }
@@ -79,40 +471,120 @@ fn create_game_(
 pub fn games_by_user(
     username: String,
     pool: &Pool,
-) -> impl Future<Item = Vec<dto::GameHeader>, Error = actix_web::Error> {
+) -> impl Future<Item = Vec<dto::GameHeader>, Error = DbError> {
     let pool = pool.clone();
-    web::block(move || games_by_user_(&username, &pool.get()?)).from_err()
+    web::block(move || games_by_user_(&username, &*pool.get()?)).from_err()
 }
 
 /// This function takes a user id and returns all games that the user is a member of.
-fn games_by_user_(username: &str, conn: &Connection) -> Result<Vec<dto::GameHeader>, Error> {
-    let mut stmt = conn.prepare(
-        "select game.id, game.description from game \
-         inner join game_member on game_member.game = game.id \
-         inner join user on user.id = game_member.user \
-         where user.username = ?1",
-    )?;
+/// Pending invitations are not included, see `invitations_by_user`.
+fn games_by_user_(username: &str, conn: &Connection) -> Result<Vec<dto::GameHeader>, DbError> {
+    games_where_(
+        "game.id in (select game_member.game from game_member \
+            inner join user on user.id = game_member.user \
+            where user.username = ?1 and game_member.ready_state != ?2)",
+        params![username, dto::ReadyState::Invited],
+        conn,
+    )
+}
 
-    let game_iter = stmt.query_map(params![username], |row| {
-        let id = row.get(0)?;
-        Ok(dto::GameHeader {
-            id,
+pub fn invitations_by_user(
+    username: String,
+    pool: &Pool,
+) -> impl Future<Item = Vec<dto::GameHeader>, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || invitations_by_user_(&username, &*pool.get()?)).from_err()
+}
+
+/// This function takes a user id and returns all games that the user is
+/// invited to, but did not accept or decline yet.
+fn invitations_by_user_(
+    username: &str,
+    conn: &Connection,
+) -> Result<Vec<dto::GameHeader>, DbError> {
+    games_where_(
+        "game.id in (select game_member.game from game_member \
+            inner join user on user.id = game_member.user \
+            where user.username = ?1 and game_member.ready_state = ?2)",
+        params![username, dto::ReadyState::Invited],
+        conn,
+    )
+}
+
+pub fn public_games(pool: &Pool) -> impl Future<Item = Vec<dto::GameHeader>, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || public_games_(&*pool.get()?)).from_err()
+}
+
+/// Returns all games that are listed publicly. Unlisted games are not
+/// included, even though everyone can see them.
+fn public_games_(conn: &Connection) -> Result<Vec<dto::GameHeader>, DbError> {
+    games_where_(
+        "game.visibility = ?1",
+        params![dto::Visibility::Public],
+        conn,
+    )
+}
+
+/// Loads all games that match the condition, together with their members.
+/// This is a single query, the members are joined to the games and the rows
+/// are grouped by game afterwards.
+fn games_where_(
+    condition: &str,
+    params: &[&dyn rusqlite::ToSql],
+    conn: &Connection,
+) -> Result<Vec<dto::GameHeader>, DbError> {
+    let mut stmt = conn.prepare(&format!(
+        "select game.id, game.description, game.status, game.visibility, \
+                game.game_type, game.rules, \
+                user.id, user.username, game_member.role, game_member.ready_state \
+         from game \
+         left join game_member on game_member.game = game.id \
+         left join user on user.id = game_member.user \
+         where {} \
+         order by game.id",
+        condition
+    ))?;
+
+    let row_iter = stmt.query_map(params, |row| {
+        let game = dto::GameHeader {
+            id: row.get(0)?,
             description: row.get(1)?,
-            members: members_by_game_(id, conn)?,
-        })
+            status: row.get(2)?,
+            visibility: row.get(3)?,
+            rules: rules_from_row(row, 4)?,
+            members: Vec::new(),
+        };
+        // A game without members has a single row with null member columns.
+        let member = match row.get::<_, Option<i64>>(6)? {
+            Some(id) => Some(dto::Member {
+                id,
+                username: row.get(7)?,
+                role: row.get(8)?,
+                ready_state: row.get(9)?,
+            }),
+            None => None,
+        };
+        Ok((game, member))
     })?;
 
-    let mut result = Vec::new();
-    for game in game_iter {
-        result.push(game?);
+    let mut games: Vec<dto::GameHeader> = Vec::new();
+    for row in row_iter {
+        let (game, member) = row?;
+        if games.last().map(|last| last.id) != Some(game.id) {
+            games.push(game);
+        }
+        if let (Some(member), Some(last)) = (member, games.last_mut()) {
+            last.members.push(member);
+        }
     }
-    Ok(result)
+    Ok(games)
 }
 
 /// This function takes a game id and returns all members of the game.
 fn members_by_game_(game: i64, conn: &Connection) -> Result<Vec<dto::Member>, rusqlite::Error> {
     let mut stmt = conn.prepare(
-        "select user.id, user.username, game_member.role, game_member.accepted from game_member \
+        "select user.id, user.username, game_member.role, game_member.ready_state from game_member \
          inner join user on user.id = game_member.user \
          where game_member.game = ?1",
     )?;
@@ -121,7 +593,7 @@ fn members_by_game_(game: i64, conn: &Connection) -> Result<Vec<dto::Member>, ru
             id: row.get(0)?,
             username: row.get(1)?,
             role: row.get(2)?,
-            accepted: row.get(3)?,
+            ready_state: row.get(3)?,
         })
     })?;
     let mut members = Vec::new();
@@ -137,7 +609,7 @@ fn member_info_(
     conn: &Connection,
 ) -> Result<Option<dto::Member>, rusqlite::Error> {
     let mut stmt = conn.prepare(
-        "select user.id, user.username, game_member.role, game_member.accepted from game_member \
+        "select user.id, user.username, game_member.role, game_member.ready_state from game_member \
          inner join user on user.id = game_member.user \
          where game_member.game = ?1
            and game_member.user = ?2",
@@ -147,7 +619,7 @@ fn member_info_(
             id: row.get(0)?,
             username: row.get(1)?,
             role: row.get(2)?,
-            accepted: row.get(3)?,
+            ready_state: row.get(3)?,
         })
     })?;
 
@@ -168,11 +640,11 @@ fn update_member_info_(
     conn.execute(
         "update game_member
         set role = ?1,
-            accepted = ?2
+            ready_state = ?2
         where user = ?3 and game = ?4",
         params![
             member_info.role,
-            member_info.accepted,
+            member_info.ready_state,
             member_info.id,
             game_id
         ],
@@ -189,56 +661,142 @@ fn insert_member_info_(
     conn: &Connection,
 ) -> Result<(), rusqlite::Error> {
     conn.execute(
-        "insert into game_member (user, game, role, accepted) values (?1, ?2, ?3, ?4)",
+        "insert into game_member (user, game, role, ready_state) values (?1, ?2, ?3, ?4)",
         params![
             member_info.id,
             game_id,
             member_info.role,
-            member_info.accepted,
+            member_info.ready_state,
         ],
     )?;
 
     Ok(())
 }
 
+/// Returns the game if the user is allowed to see it. Games that are hidden
+/// from the user are treated like games that do not exist.
 pub fn game(
+    username: Option<String>,
+    game_id: i64,
+    pool: &Pool,
+) -> impl Future<Item = dto::GameHeader, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool.get()?;
+        check_can_see_game_(username.as_deref(), game_id, &conn)?;
+        game_(game_id, &conn)?.ok_or(DbError::GameNotFound)
+    })
+    .from_err()
+}
+
+/// Returns the game without checking its visibility. This is only meant for
+/// data that is send to the members of the game, e.g. push messages.
+pub fn game_unchecked(
     game_id: i64,
     pool: &Pool,
-) -> impl Future<Item = Option<dto::GameHeader>, Error = actix_web::Error> {
+) -> impl Future<Item = Option<dto::GameHeader>, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || game_(game_id, &*pool.get()?)).from_err()
+}
+
+fn game_(game_id: i64, conn: &Connection) -> Result<Option<dto::GameHeader>, DbError> {
+    Ok(games_where_("game.id = ?1", params![game_id], conn)?.pop())
+}
+
+pub fn friends_by_user(
+    username: String,
+    pool: &Pool,
+) -> impl Future<Item = Vec<dto::UserInfo>, Error = DbError> {
     let pool = pool.clone();
-    web::block(move || game_(game_id, &pool.get()?)).from_err()
+    web::block(move || friends_by_user_(&username, &*pool.get()?)).from_err()
 }
 
-fn game_(game_id: i64, conn: &Connection) -> Result<Option<dto::GameHeader>, Error> {
+/// Returns all users that have a confirmed friendship with the user.
+fn friends_by_user_(username: &str, conn: &Connection) -> Result<Vec<dto::UserInfo>, DbError> {
+    let user_id = user_id_(username.to_owned(), conn)?;
     let mut stmt = conn.prepare(
-        "select game.description from game \
-         where game.id = ?1",
+        "select user.id, user.username from user \
+         inner join friendship \
+            on (friendship.user = ?1 and friendship.friend = user.id) \
+            or (friendship.friend = ?1 and friendship.user = user.id) \
+         where friendship.accepted = 1",
     )?;
 
-    let mut game_iter = stmt.query_map(params![game_id], |row| {
-        Ok(dto::GameHeader {
-            id: game_id,
-            description: row.get(0)?,
-            members: members_by_game_(game_id, conn)?,
+    let user_iter = stmt.query_map(params![user_id], |row| {
+        Ok(dto::UserInfo {
+            id: row.get(0)?,
+            username: row.get(1)?,
         })
     })?;
 
-    if let Some(row) = game_iter.next() {
-        Ok(Some(row?))
-    } else {
-        Ok(None)
+    let mut users = Vec::new();
+    for user in user_iter {
+        users.push(user?);
     }
+    Ok(users)
 }
 
-pub fn all_users(pool: &Pool) -> impl Future<Item = Vec<dto::UserInfo>, Error = actix_web::Error> {
+pub fn friend_requests_by_user(
+    username: String,
+    pool: &Pool,
+) -> impl Future<Item = dto::FriendRequests, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || friend_requests_by_user_(&username, &*pool.get()?)).from_err()
+}
+
+fn friend_requests_by_user_(
+    username: &str,
+    conn: &Connection,
+) -> Result<dto::FriendRequests, DbError> {
+    let user_id = user_id_(username.to_owned(), conn)?;
+
+    let incoming = users_by_query_(
+        "select user.id, user.username from user \
+         inner join friendship on friendship.user = user.id \
+         where friendship.friend = ?1 and friendship.accepted = 0",
+        user_id,
+        conn,
+    )?;
+    let outgoing = users_by_query_(
+        "select user.id, user.username from user \
+         inner join friendship on friendship.friend = user.id \
+         where friendship.user = ?1 and friendship.accepted = 0",
+        user_id,
+        conn,
+    )?;
+
+    Ok(dto::FriendRequests { incoming, outgoing })
+}
+
+pub fn blocked_users(
+    username: String,
+    pool: &Pool,
+) -> impl Future<Item = Vec<dto::UserInfo>, Error = DbError> {
     let pool = pool.clone();
-    web::block(move || all_users_(&pool.get()?)).from_err()
+    web::block(move || {
+        let conn = pool.get()?;
+        let user_id = user_id_(username, &conn)?;
+        users_by_query_(
+            "select user.id, user.username from user \
+             inner join user_block on user_block.blocked_user = user.id \
+             where user_block.user = ?1",
+            user_id,
+            &conn,
+        )
+    })
+    .from_err()
 }
 
-fn all_users_(conn: &Connection) -> Result<Vec<dto::UserInfo>, Error> {
-    let mut stmt = conn.prepare("select id, username from user")?;
+/// Runs a query that selects the id and username of users, given the id of
+/// another user as its only parameter.
+fn users_by_query_(
+    query: &str,
+    user_id: i64,
+    conn: &Connection,
+) -> Result<Vec<dto::UserInfo>, DbError> {
+    let mut stmt = conn.prepare(query)?;
 
-    let user_iter = stmt.query_map(params![], |row| {
+    let user_iter = stmt.query_map(params![user_id], |row| {
         Ok(dto::UserInfo {
             id: row.get(0)?,
             username: row.get(1)?,
@@ -252,93 +810,1145 @@ fn all_users_(conn: &Connection) -> Result<Vec<dto::UserInfo>, Error> {
     Ok(users)
 }
 
-pub fn update_description(
+pub fn request_friendship(
     username: String,
-    game_id: i64,
-    new_description: String,
+    friend: String,
     pool: &Pool,
-) -> impl Future<Item = (), Error = actix_web::Error> {
+) -> impl Future<Item = (), Error = DbError> {
     let pool = pool.clone();
-    web::block(move || update_description_(username, game_id, new_description, &pool.get()?))
-        .from_err()
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            request_friendship_(username.clone(), friend.clone(), conn)
+        })
+    })
+    .from_err()
 }
 
-fn update_description_(
-    username: String,
-    game_id: i64,
-    new_description: String,
-    conn: &Connection,
-) -> Result<(), Error> {
-    // TODO: The database module will contain business logic, until actix
-    // updates to async await. Then we can move it outside.
+/// Sends a friend request. When the other user already asked for the
+/// friendship, it is accepted instead. Repeated requests are ignored.
+fn request_friendship_(username: String, friend: String, conn: &Connection) -> Result<(), DbError> {
+    let (user_id, friend_id) = friend_ids_(username, friend, conn)?;
 
-    // Check if the user is a member of the game
-    let user_id = get_user_id_(username, conn)?;
-    let members = members_by_game_(game_id, conn)?;
+    if is_blocked_(user_id, friend_id, conn)? || is_blocked_(friend_id, user_id, conn)? {
+        return Err(DbError::Blocked);
+    }
 
-    if members.iter().any(|member| Some(member.id) == user_id) {
+    // The request in the opposite direction is accepted, if there is one.
+    let accepted = conn.execute(
+        "update friendship set accepted = 1 where user = ?1 and friend = ?2",
+        params![friend_id, user_id],
+    )?;
+    if accepted == 0 {
         conn.execute(
-            "update game set description = ?1 where id = ?2",
-            params![new_description, game_id],
+            "insert or ignore into friendship (user, friend, accepted) values (?1, ?2, 0)",
+            params![user_id, friend_id],
         )?;
-
-        Ok(())
-    } else {
-        // TODO: Fail with error
-        Ok(())
     }
+
+    Ok(())
 }
 
-fn get_user_id_(username: String, conn: &Connection) -> Result<Option<i64>, Error> {
-    let mut stmt = conn.prepare("select id from user where username = ?1")?;
+pub fn accept_friendship(
+    username: String,
+    friend: String,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            accept_friendship_(username.clone(), friend.clone(), conn)
+        })
+    })
+    .from_err()
+}
 
-    let mut user_iter = stmt.query_map(params![username], |row| Ok(row.get(0)?))?;
+fn accept_friendship_(username: String, friend: String, conn: &Connection) -> Result<(), DbError> {
+    let (user_id, friend_id) = friend_ids_(username, friend, conn)?;
 
-    if let Some(row) = user_iter.next() {
-        Ok(Some(row?))
-    } else {
-        Ok(None)
+    let accepted = conn.execute(
+        "update friendship set accepted = 1 \
+         where user = ?1 and friend = ?2 and accepted = 0",
+        params![friend_id, user_id],
+    )?;
+    if accepted == 0 {
+        return Err(DbError::NoFriendRequest);
     }
+
+    Ok(())
 }
 
-pub fn update_member(
+pub fn remove_friendship(
     username: String,
-    game_id: i64,
-    new_member: dto::Member,
+    friend: String,
     pool: &Pool,
-) -> impl Future<Item = (), Error = actix_web::Error> {
+) -> impl Future<Item = (), Error = DbError> {
     let pool = pool.clone();
-    web::block(move || update_member_(username, game_id, new_member, &pool.get()?)).from_err()
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            let (user_id, friend_id) = friend_ids_(username.clone(), friend.clone(), conn)?;
+            remove_friendship_(user_id, friend_id, conn)
+        })
+    })
+    .from_err()
 }
 
-fn update_member_(
+/// Removes the friendship or pending requests between the users, in both
+/// directions.
+fn remove_friendship_(user_id: i64, friend_id: i64, conn: &Connection) -> Result<(), DbError> {
+    conn.execute(
+        "delete from friendship \
+         where (user = ?1 and friend = ?2) or (user = ?2 and friend = ?1)",
+        params![user_id, friend_id],
+    )?;
+    Ok(())
+}
+
+pub fn block_user(
     username: String,
-    game_id: i64,
-    mut new_member: dto::Member,
-    conn: &Connection,
-) -> Result<(), Error> {
-    // TODO: The database module will contain business logic, until actix
-    // updates to async await. Then we can move it outside.
+    blocked_user: String,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            block_user_(username.clone(), blocked_user.clone(), conn)
+        })
+    })
+    .from_err()
+}
 
-    if let Some(user_id) = get_user_id_(username, conn)? {
-        if member_info_(game_id, user_id, conn)?.is_none() {
-            // The user giving the command is not part of the game.
-            return Ok(());
-        } else if let Some(mut member_info) = member_info_(game_id, new_member.id, conn)? {
-            member_info.role = new_member.role;
+fn block_user_(username: String, blocked_user: String, conn: &Connection) -> Result<(), DbError> {
+    let (user_id, blocked_id) = friend_ids_(username, blocked_user, conn)?;
 
-            update_member_info_(game_id, member_info, conn)?;
-        } else {
-            // We make sure that the client can't decide to accept the request
-            // for another user.
-            new_member.accepted = false;
+    remove_friendship_(user_id, blocked_id, conn)?;
+    conn.execute(
+        "insert or ignore into user_block (user, blocked_user) values (?1, ?2)",
+        params![user_id, blocked_id],
+    )?;
+    Ok(())
+}
 
-            insert_member_info_(game_id, new_member, conn)?;
-        }
+pub fn unblock_user(
+    username: String,
+    blocked_user: String,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            let (user_id, blocked_id) = friend_ids_(username.clone(), blocked_user.clone(), conn)?;
+            conn.execute(
+                "delete from user_block where user = ?1 and blocked_user = ?2",
+                params![user_id, blocked_id],
+            )?;
+            Ok(())
+        })
+    })
+    .from_err()
+}
 
-        Ok(())
-    } else {
-        // TODO: Fail with error
-        Ok(())
+/// Looks up the ids of two users that are about to change their friendship.
+/// Users can't be friends with themself.
+fn friend_ids_(username: String, friend: String, conn: &Connection) -> Result<(i64, i64), DbError> {
+    let user_id = user_id_(username, conn)?;
+    let friend_id = user_id_(friend, conn)?;
+    if user_id == friend_id {
+        return Err(DbError::FriendIsYourself);
+    }
+    Ok((user_id, friend_id))
+}
+
+/// True if the first user blocked the second user.
+fn is_blocked_(user_id: i64, blocked_id: i64, conn: &Connection) -> Result<bool, DbError> {
+    let mut stmt =
+        conn.prepare("select 1 from user_block where user = ?1 and blocked_user = ?2")?;
+    Ok(stmt.exists(params![user_id, blocked_id])?)
+}
+
+fn are_friends_(user_id: i64, friend_id: i64, conn: &Connection) -> Result<bool, DbError> {
+    let mut stmt = conn.prepare(
+        "select 1 from friendship \
+         where ((user = ?1 and friend = ?2) or (user = ?2 and friend = ?1)) \
+           and accepted = 1",
+    )?;
+    Ok(stmt.exists(params![user_id, friend_id])?)
+}
+
+pub fn update_description(
+    username: String,
+    game_id: i64,
+    new_description: String,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            update_description_(username.clone(), game_id, new_description.clone(), conn)
+        })
+    })
+    .from_err()
+}
+
+fn update_description_(
+    username: String,
+    game_id: i64,
+    new_description: String,
+    conn: &Connection,
+) -> Result<(), DbError> {
+    // TODO: The database module will contain business logic, until actix
+    // updates to async await. Then we can move it outside.
+
+    // Check if the user is a member of the game
+    own_member_info_(username, game_id, conn)?;
+
+    conn.execute(
+        "update game set description = ?1 where id = ?2",
+        params![new_description, game_id],
+    )?;
+
+    Ok(())
+}
+
+pub fn update_visibility(
+    username: String,
+    game_id: i64,
+    visibility: dto::Visibility,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            update_visibility_(username.clone(), game_id, visibility, conn)
+        })
+    })
+    .from_err()
+}
+
+fn update_visibility_(
+    username: String,
+    game_id: i64,
+    visibility: dto::Visibility,
+    conn: &Connection,
+) -> Result<(), DbError> {
+    // Check if the user is a member of the game
+    own_member_info_(username, game_id, conn)?;
+
+    conn.execute(
+        "update game set visibility = ?1 where id = ?2",
+        params![visibility, game_id],
+    )?;
+
+    Ok(())
+}
+
+/// Looks up the id of a user. Fails if there is no user with this name.
+fn user_id_(username: String, conn: &Connection) -> Result<i64, DbError> {
+    let mut stmt = conn.prepare("select id from user where username = ?1")?;
+
+    let mut user_iter = stmt.query_map(params![username], |row| row.get(0))?;
+
+    if let Some(row) = user_iter.next() {
+        Ok(row?)
+    } else {
+        Err(DbError::UnknownUser)
+    }
+}
+
+pub fn update_member(
+    username: String,
+    game_id: i64,
+    new_member: dto::Member,
+    invite_friends_only: bool,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            update_member_(
+                username.clone(),
+                game_id,
+                new_member.clone(),
+                invite_friends_only,
+                conn,
+            )
+        })
+    })
+    .from_err()
+}
+
+/// Users who blocked the user giving the command can't be invited. If
+/// `invite_friends_only` is set, only friends can be invited.
+fn update_member_(
+    username: String,
+    game_id: i64,
+    mut new_member: dto::Member,
+    invite_friends_only: bool,
+    conn: &Connection,
+) -> Result<(), DbError> {
+    // TODO: The database module will contain business logic, until actix
+    // updates to async await. Then we can move it outside.
+
+    // The user giving the command must be part of the game.
+    let own_member_info = own_member_info_(username, game_id, conn)?;
+
+    if game_status_(game_id, conn)? != Some(dto::GameStatus::Setup) {
+        // The seats are fixed once the game started.
+        return Err(DbError::GameAlreadyStarted);
+    }
+
+    if let Some(mut member_info) = member_info_(game_id, new_member.id, conn)? {
+        // Moving to another seat requires the player to declare
+        // themself ready again.
+        if member_info.role != new_member.role && member_info.ready_state == dto::ReadyState::Ready
+        {
+            member_info.ready_state = dto::ReadyState::Accepted;
+        }
+        member_info.role = new_member.role;
+
+        update_member_info_(game_id, member_info, conn)?;
+    } else if user_exists_(new_member.id, conn)? {
+        if is_blocked_(new_member.id, own_member_info.id, conn)? {
+            return Err(DbError::Blocked);
+        }
+        if invite_friends_only && !are_friends_(own_member_info.id, new_member.id, conn)? {
+            return Err(DbError::NotAFriend);
+        }
+
+        // We make sure that the client can't decide to accept the request
+        // for another user.
+        new_member.ready_state = dto::ReadyState::Invited;
+
+        insert_member_info_(game_id, new_member, conn)?;
+    } else {
+        return Err(DbError::UnknownUser);
+    }
+
+    // Moving a member may leave every seat with a single ready player.
+    start_game_if_ready_(game_id, conn)
+}
+
+fn user_exists_(user_id: i64, conn: &Connection) -> Result<bool, DbError> {
+    let mut stmt = conn.prepare("select 1 from user where id = ?1")?;
+    Ok(stmt.exists(params![user_id])?)
+}
+
+fn game_status_(game_id: i64, conn: &Connection) -> Result<Option<dto::GameStatus>, DbError> {
+    let mut stmt = conn.prepare("select status from game where id = ?1")?;
+
+    let mut status_iter = stmt.query_map(params![game_id], |row| row.get(0))?;
+
+    if let Some(row) = status_iter.next() {
+        Ok(Some(row?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The member info of the user giving a setup command. Fails if they are not
+/// part of the game. Invited users only become members once they accept.
+fn own_member_info_(
+    username: String,
+    game_id: i64,
+    conn: &Connection,
+) -> Result<dto::Member, DbError> {
+    let member_info = own_invitation_or_member_info_(username, game_id, conn)?;
+    if member_info.ready_state == dto::ReadyState::Invited {
+        return Err(DbError::NotAMember);
+    }
+    Ok(member_info)
+}
+
+/// Like `own_member_info_`, but also returns pending invitations.
+fn own_invitation_or_member_info_(
+    username: String,
+    game_id: i64,
+    conn: &Connection,
+) -> Result<dto::Member, DbError> {
+    if !game_exists_(game_id, conn)? {
+        return Err(DbError::GameNotFound);
+    }
+    let user_id = user_id_(username, conn)?;
+    member_info_(game_id, user_id, conn)?.ok_or(DbError::NotAMember)
+}
+
+pub fn accept_invitation(
+    username: String,
+    game_id: i64,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            accept_invitation_(username.clone(), game_id, conn)
+        })
+    })
+    .from_err()
+}
+
+fn accept_invitation_(username: String, game_id: i64, conn: &Connection) -> Result<(), DbError> {
+    let mut member_info = own_invitation_or_member_info_(username, game_id, conn)?;
+    if member_info.ready_state != dto::ReadyState::Invited {
+        return Err(DbError::NotInvited);
+    }
+
+    member_info.ready_state = dto::ReadyState::Accepted;
+    update_member_info_(game_id, member_info, conn)?;
+    Ok(())
+}
+
+pub fn decline_invitation(
+    username: String,
+    game_id: i64,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            decline_invitation_(username.clone(), game_id, conn)
+        })
+    })
+    .from_err()
+}
+
+fn decline_invitation_(username: String, game_id: i64, conn: &Connection) -> Result<(), DbError> {
+    let member_info = own_invitation_or_member_info_(username, game_id, conn)?;
+    if member_info.ready_state != dto::ReadyState::Invited {
+        return Err(DbError::NotInvited);
+    }
+
+    conn.execute(
+        "delete from game_member where user = ?1 and game = ?2",
+        params![member_info.id, game_id],
+    )?;
+    // The member may have blocked a seat for the ready players.
+    start_game_if_ready_(game_id, conn)
+}
+
+pub fn leave_game(
+    username: String,
+    game_id: i64,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || in_transaction(&pool, |conn| leave_game_(username.clone(), game_id, conn)))
+        .from_err()
+}
+
+/// Removes the user from the game. While the game is running, the players
+/// are bound to their seats and only watchers can leave.
+fn leave_game_(username: String, game_id: i64, conn: &Connection) -> Result<(), DbError> {
+    let member_info = own_member_info_(username, game_id, conn)?;
+
+    if member_info.role.player_index().is_some()
+        && game_status_(game_id, conn)? == Some(dto::GameStatus::Running)
+    {
+        return Err(DbError::GameAlreadyStarted);
+    }
+
+    conn.execute(
+        "delete from game_member where user = ?1 and game = ?2",
+        params![member_info.id, game_id],
+    )?;
+    // The member may have blocked a seat for the ready players.
+    start_game_if_ready_(game_id, conn)
+}
+
+pub fn set_ready(
+    username: String,
+    game_id: i64,
+    ready: bool,
+    pool: &Pool,
+) -> impl Future<Item = (), Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            set_ready_(username.clone(), game_id, ready, conn)
+        })
+    })
+    .from_err()
+}
+
+/// Marks a player as (not) ready. When the last player becomes ready, the
+/// game starts.
+fn set_ready_(
+    username: String,
+    game_id: i64,
+    ready: bool,
+    conn: &Connection,
+) -> Result<(), DbError> {
+    let mut member_info = own_member_info_(username, game_id, conn)?;
+    if member_info.role.player_index().is_none() {
+        return Err(DbError::NotAPlayer);
+    }
+
+    if game_status_(game_id, conn)? != Some(dto::GameStatus::Setup) {
+        return Err(DbError::GameAlreadyStarted);
+    }
+
+    member_info.ready_state = if ready {
+        dto::ReadyState::Ready
+    } else {
+        dto::ReadyState::Accepted
+    };
+    update_member_info_(game_id, member_info, conn)?;
+
+    start_game_if_ready_(game_id, conn)?;
+
+    Ok(())
+}
+
+/// Starts the game once every player seat is held by exactly one member and
+/// all of these players are ready. This is checked after every change to the
+/// members of a game in setup.
+fn start_game_if_ready_(game_id: i64, conn: &Connection) -> Result<(), DbError> {
+    if game_status_(game_id, conn)? != Some(dto::GameStatus::Setup) {
+        return Ok(());
+    }
+    let members = members_by_game_(game_id, conn)?;
+    let initial_state = initial_state_(game_id, conn)?;
+
+    let all_seats_ready = (0..initial_state.player_count()).all(|seat| {
+        let mut players = members
+            .iter()
+            .filter(|member| member.role.player_index() == Some(seat));
+        match (players.next(), players.next()) {
+            (Some(player), None) => player.ready_state == dto::ReadyState::Ready,
+            _ => false,
+        }
+    });
+
+    if all_seats_ready {
+        // The state of the game is rebuilt from the stored actions, so the
+        // game starts in its initial state with an empty list of actions.
+        conn.execute("delete from game_data where game = ?1", params![game_id])?;
+        conn.execute(
+            "update game set status = ?1 where id = ?2",
+            params![dto::GameStatus::Running, game_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads the rules from the game type column at `index` and the rules column
+/// after it.
+fn rules_from_row(row: &rusqlite::Row, index: usize) -> rusqlite::Result<GameRules> {
+    let json: String = row.get(index + 1)?;
+    GameRules::from_json(row.get(index)?, &json).map_err(|error| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index + 1,
+            rusqlite::types::Type::Text,
+            Box::new(error),
+        )
+    })
+}
+
+/// The state of a game before the first action, this depends on the rules
+/// the game was created with.
+fn initial_state_(game_id: i64, conn: &Connection) -> Result<GameState, DbError> {
+    let mut stmt = conn.prepare("select game_type, rules from game where id = ?1")?;
+    let mut rules_iter = stmt.query_map(params![game_id], |row| rules_from_row(row, 0))?;
+    match rules_iter.next() {
+        Some(rules) => Ok(GameState::new(rules?)),
+        None => Err(DbError::GameNotFound),
+    }
+}
+
+pub fn game_state(
+    username: Option<String>,
+    game_id: i64,
+    action_count: Option<usize>,
+    pool: &Pool,
+) -> impl Future<Item = GameState, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool.get()?;
+        check_can_see_game_(username.as_deref(), game_id, &conn)?;
+        game_state_(game_id, action_count, &conn)?.ok_or(DbError::GameNotFound)
+    })
+    .from_err()
+}
+
+/// Like `game_state`, but returns `None` unless the game is running.
+pub fn running_game_state(
+    username: Option<String>,
+    game_id: i64,
+    pool: &Pool,
+) -> impl Future<Item = Option<GameState>, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool.get()?;
+        check_can_see_game_(username.as_deref(), game_id, &conn)?;
+        match game_status_(game_id, &conn)? {
+            None => Err(DbError::GameNotFound),
+            Some(dto::GameStatus::Running) => game_state_(game_id, None, &conn),
+            Some(_) => Ok(None),
+        }
+    })
+    .from_err()
+}
+
+/// Like `game_state`, but fails unless the game is finished.
+pub fn finished_game_state(
+    username: Option<String>,
+    game_id: i64,
+    action_count: Option<usize>,
+    pool: &Pool,
+) -> impl Future<Item = GameState, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool.get()?;
+        check_can_see_game_(username.as_deref(), game_id, &conn)?;
+        match game_status_(game_id, &conn)? {
+            None => Err(DbError::GameNotFound),
+            Some(dto::GameStatus::Finished) => {
+                game_state_(game_id, action_count, &conn)?.ok_or(DbError::GameNotFound)
+            }
+            Some(_) => Err(DbError::GameNotFinished),
+        }
+    })
+    .from_err()
+}
+
+/// Returns the state of the game by replaying the stored actions. If an
+/// action count is given, only that many actions are replayed, which gives
+/// the position at that point of the game.
+fn game_state_(
+    game_id: i64,
+    action_count: Option<usize>,
+    conn: &Connection,
+) -> Result<Option<GameState>, DbError> {
+    if let Some(mut actions) = game_history_(game_id, conn)? {
+        if let Some(action_count) = action_count {
+            actions.truncate(action_count);
+        }
+        Ok(Some(game::replay(
+            initial_state_(game_id, conn)?,
+            &actions,
+        )?))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn game_history(
+    username: Option<String>,
+    game_id: i64,
+    pool: &Pool,
+) -> impl Future<Item = Vec<GameAction>, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool.get()?;
+        check_can_see_game_(username.as_deref(), game_id, &conn)?;
+        game_history_(game_id, &conn)?.ok_or(DbError::GameNotFound)
+    })
+    .from_err()
+}
+
+/// Returns all actions that were executed in the game, in order.
+fn game_history_(game_id: i64, conn: &Connection) -> Result<Option<Vec<GameAction>>, DbError> {
+    if !game_exists_(game_id, conn)? {
+        return Ok(None);
+    }
+
+    let mut stmt = conn.prepare("select data from game_data where game = ?1 order by id")?;
+
+    let data_iter = stmt.query_map(params![game_id], |row| row.get::<_, String>(0))?;
+
+    let mut actions = Vec::new();
+    for data in data_iter {
+        actions.push(serde_json::from_str(&data?)?);
+    }
+    Ok(Some(actions))
+}
+
+fn game_exists_(game_id: i64, conn: &Connection) -> Result<bool, DbError> {
+    let mut stmt = conn.prepare("select 1 from game where id = ?1")?;
+    Ok(stmt.exists(params![game_id])?)
+}
+
+/// Fails with `GameNotFound` if the user may not see the game, so hidden
+/// games can't be told apart from games that do not exist.
+fn check_can_see_game_(
+    username: Option<&str>,
+    game_id: i64,
+    conn: &Connection,
+) -> Result<(), DbError> {
+    if can_see_game_(username, game_id, conn)? {
+        Ok(())
+    } else {
+        Err(DbError::GameNotFound)
+    }
+}
+
+/// Every endpoint that reads a game must check this first. Members can see
+/// their games, this includes users with a pending invitation. Users that
+/// are not logged in can only see public and unlisted games.
+fn can_see_game_(username: Option<&str>, game_id: i64, conn: &Connection) -> Result<bool, DbError> {
+    let mut stmt = conn.prepare("select visibility from game where id = ?1")?;
+    let mut visibility_iter = stmt.query_map(params![game_id], |row| row.get(0))?;
+
+    let visibility: dto::Visibility = match visibility_iter.next() {
+        Some(row) => row?,
+        None => return Ok(false),
+    };
+    if visibility != dto::Visibility::MembersOnly {
+        return Ok(true);
+    }
+
+    match username {
+        Some(username) => {
+            let mut stmt = conn.prepare(
+                "select 1 from game_member \
+                 inner join user on user.id = game_member.user \
+                 where user.username = ?1 and game_member.game = ?2",
+            )?;
+            Ok(stmt.exists(params![username, game_id])?)
+        }
+        None => Ok(false),
+    }
+}
+
+pub fn execute_action(
+    username: String,
+    game_id: i64,
+    action: GameAction,
+    pool: &Pool,
+) -> impl Future<Item = GameState, Error = DbError> {
+    let pool = pool.clone();
+    web::block(move || {
+        in_transaction(&pool, |conn| {
+            execute_action_(username.clone(), game_id, action.clone(), conn)
+        })
+    })
+    .from_err()
+}
+
+fn execute_action_(
+    username: String,
+    game_id: i64,
+    action: GameAction,
+    conn: &Connection,
+) -> Result<GameState, DbError> {
+    // TODO: The database module will contain business logic, until actix
+    // updates to async await. Then we can move it outside.
+
+    match game_status_(game_id, conn)? {
+        None => return Err(DbError::GameNotFound),
+        // An administrator may finish a game whose state is not over yet.
+        Some(dto::GameStatus::Setup) | Some(dto::GameStatus::Finished) => {
+            return Err(DbError::GameNotRunning)
+        }
+        Some(dto::GameStatus::Running) => {}
+    }
+
+    let mut state = match game_state_(game_id, None, conn)? {
+        Some(state) => state,
+        None => return Err(DbError::GameNotFound),
+    };
+
+    // Only members who accepted a player seat can act.
+    let user_id = user_id_(username, conn)?;
+    let player = member_info_(game_id, user_id, conn)?
+        .filter(|member| member.ready_state == dto::ReadyState::Ready)
+        .and_then(|member| member.role.player_index())
+        .filter(|player| *player < state.player_count());
+
+    match player {
+        None => Err(DbError::NotAPlayer),
+        Some(player) if player != state.current_player() && !state.is_over() => {
+            Err(DbError::NotYourTurn)
+        }
+        Some(_) => {
+            if let Err(error) = state.execute(&action) {
+                return Err(DbError::IllegalAction(error));
+            }
+
+            // The action is stored as an event, the state is always rebuilt
+            // from the list of events.
+            conn.execute(
+                "insert into game_data (game, data) values (?1, ?2)",
+                params![game_id, serde_json::to_string(&action)?],
+            )?;
+
+            if state.is_over() {
+                conn.execute(
+                    "update game set status = ?1 where id = ?2",
+                    params![dto::GameStatus::Finished, game_id],
+                )?;
+            }
+
+            Ok(state)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::heap_nim::HeapNimRules;
+    use crate::nim::NimRules;
+    #[test]
+    fn read_hash_iteration_count() {
+        let dummy_hash = dummy_password_hash(20).unwrap();
+        assert_eq!(hash_iteration_count(&dummy_hash), Some(20));
+        assert_eq!(dummy_password_hash(20).unwrap(), dummy_hash);
+        assert_eq!(
+            hash_iteration_count(&dummy_password_hash(30).unwrap()),
+            Some(30)
+        );
+        let password_hash = pbkdf2::pbkdf2_simple("password", 20).unwrap();
+        assert_eq!(hash_iteration_count(&password_hash), Some(20));
+        assert_eq!(hash_iteration_count("not a hash"), None);
+    }
+
+    #[test]
+    fn upgrade_weak_password_hashes() {
+        let manager = r2d2_sqlite::SqliteConnectionManager::memory();
+        let pool = Pool::builder().max_size(1).build(manager).unwrap();
+        crate::migration::migrate(&mut pool.get().unwrap()).unwrap();
+        let weak_hash = pbkdf2::pbkdf2_simple("password", 20).unwrap();
+        pool.get()
+            .unwrap()
+            .execute(
+                "insert into user (username, password) values ('rolf', ?1)",
+                params![weak_hash],
+            )
+            .unwrap();
+        let name = || "rolf".to_owned();
+        let stored_count = || {
+            let hash = password_hash_("rolf", &pool.get().unwrap())
+                .unwrap()
+                .unwrap();
+            hash_iteration_count(&hash)
+        };
+
+        let change = change_password(
+            name(),
+            "wrong".to_owned(),
+            "new password".to_owned(),
+            30,
+            &pool,
+        );
+        match change.wait() {
+            Err(DbError::WrongPassword) => {}
+            _ => panic!("The old password must be known to change it."),
+        }
+        assert!(!check_password(name(), "wrong".to_owned(), 30, &pool)
+            .wait()
+            .unwrap());
+        assert_eq!(stored_count(), Some(20));
+
+        assert!(check_password(name(), "password".to_owned(), 30, &pool)
+            .wait()
+            .unwrap());
+        assert_eq!(stored_count(), Some(30));
+        assert!(check_password(name(), "password".to_owned(), 30, &pool)
+            .wait()
+            .unwrap());
+    }
+
+    #[test]
+    fn reject_taken_usernames() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn).unwrap();
+        register_user_("rolf", "first hash", &conn).unwrap();
+        match register_user_("rolf", "second hash", &conn) {
+            Err(DbError::UsernameTaken) => {}
+            _ => panic!("Usernames are unique."),
+        }
+        assert_eq!(
+            password_hash_("rolf", &conn).unwrap(),
+            Some("first hash".to_owned())
+        );
+    }
+
+    #[test]
+    fn group_members_by_game() {
+        let manager = r2d2_sqlite::SqliteConnectionManager::memory();
+        let conn = Pool::builder()
+            .max_size(1)
+            .build(manager)
+            .unwrap()
+            .get()
+            .unwrap();
+        conn.execute_batch(
+            "create table user (id integer primary key, username text, password text);
+             create table game (id integer primary key, description text,
+                                status integer, visibility integer,
+                                game_type integer default 1, rules text default '{}');
+             create table game_member (user integer, game integer,
+                                       role integer, ready_state integer);
+             insert into user (id, username) values (1, 'rolf'), (2, 'doro');
+             insert into game (id, description, status, visibility)
+                 values (1, 'shared', 1, 2), (2, 'empty', 1, 1), (3, 'doro', 1, 1);
+             insert into game_member values (1, 1, 1, 2), (2, 1, 2, 1), (2, 3, 1, 2);",
+        )
+        .unwrap();
+
+        let games = games_where_("game.visibility = ?1 or game.id = 1", params![1], &conn).unwrap();
+        let members: Vec<(i64, usize)> = games.iter().map(|g| (g.id, g.members.len())).collect();
+        assert_eq!(members, vec![(1, 2), (2, 0), (3, 1)]);
+
+        let games = games_by_user_("rolf", &conn).unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].members.len(), 2);
+        assert_eq!(invitations_by_user_("doro", &conn).unwrap()[0].id, 1);
+    }
+
+    #[test]
+    fn store_rules_with_game() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn).unwrap();
+        conn.execute_batch("insert into user (id, username) values (1, 'rolf')")
+            .unwrap();
+
+        let create = |rules: GameRules| dto::GameCreate {
+            description: "rules".to_owned(),
+            visibility: dto::Visibility::default(),
+            rules,
+        };
+        let rules = GameRules::Nim(NimRules {
+            token_count: 7,
+            contiguous: true,
+            ..NimRules::default()
+        });
+        let heap_rules = GameRules::HeapNim(HeapNimRules {
+            heaps: vec![1, 2, 3],
+            ..HeapNimRules::default()
+        });
+        for rules in [rules, heap_rules] {
+            let game = create_game_("rolf".to_owned(), create(rules.clone()), &conn).unwrap();
+            assert_eq!(game_(game.id, &conn).unwrap().unwrap().rules, rules);
+            assert_eq!(
+                initial_state_(game.id, &conn).unwrap(),
+                GameState::new(rules)
+            );
+        }
+
+        let three_players = GameRules::Nim(NimRules {
+            player_count: 3,
+            ..NimRules::default()
+        });
+        match create_game_("rolf".to_owned(), create(three_players), &conn) {
+            Err(DbError::InvalidRules(RulesError::PlayerCount)) => {}
+            _ => panic!("There are only two player seats."),
+        }
+    }
+
+    #[test]
+    fn reject_actions_in_finished_games() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn).unwrap();
+        conn.execute_batch("insert into user (id, username) values (1, 'rolf'), (2, 'doro')")
+            .unwrap();
+        let create = dto::GameCreate {
+            description: "finish".to_owned(),
+            visibility: dto::Visibility::default(),
+            rules: GameRules::default(),
+        };
+        let game = create_game_("rolf".to_owned(), create, &conn).unwrap();
+        conn.execute(
+            "insert into game_member (user, game, role, ready_state) values (2, ?1, 2, 3)",
+            params![game.id],
+        )
+        .unwrap();
+        conn.execute(
+            "update game_member set ready_state = 3 where user = 1 and game = ?1",
+            params![game.id],
+        )
+        .unwrap();
+        start_game_if_ready_(game.id, &conn).unwrap();
+
+        let action: GameAction = serde_json::from_str(r#"{"token_indices":[0]}"#).unwrap();
+        execute_action_("rolf".to_owned(), game.id, action.clone(), &conn).unwrap();
+
+        // The game is finished by an administrator while it is not over.
+        conn.execute(
+            "update game set status = ?1 where id = ?2",
+            params![dto::GameStatus::Finished, game.id],
+        )
+        .unwrap();
+        match execute_action_("doro".to_owned(), game.id, action, &conn) {
+            Err(DbError::GameNotRunning) => {}
+            _ => panic!("Finished games accept no actions."),
+        }
+        assert_eq!(game_history_(game.id, &conn).unwrap().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn invited_users_can_only_answer_the_invitation() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn).unwrap();
+        conn.execute_batch("insert into user (id, username) values (1, 'rolf'), (2, 'doro')")
+            .unwrap();
+        let create = dto::GameCreate {
+            description: "invite".to_owned(),
+            visibility: dto::Visibility::default(),
+            rules: GameRules::default(),
+        };
+        let game = create_game_("rolf".to_owned(), create, &conn).unwrap();
+        let invitation = dto::Member {
+            id: 2,
+            username: "doro".to_owned(),
+            role: dto::MemberRole::Watcher,
+            ready_state: dto::ReadyState::Invited,
+        };
+        update_member_("rolf".to_owned(), game.id, invitation.clone(), false, &conn).unwrap();
+        let take_seat = dto::Member {
+            role: dto::MemberRole::BlackPlayer,
+            ..invitation
+        };
+
+        let doro = || "doro".to_owned();
+        let results = vec![
+            update_description_(doro(), game.id, "mine".to_owned(), &conn),
+            update_visibility_(doro(), game.id, dto::Visibility::Public, &conn),
+            update_member_(doro(), game.id, take_seat, false, &conn),
+            set_ready_(doro(), game.id, true, &conn),
+            leave_game_(doro(), game.id, &conn),
+        ];
+        for result in results {
+            match result {
+                Err(DbError::NotAMember) => {}
+                _ => panic!("Invited users are not members yet."),
+            }
+        }
+
+        accept_invitation_(doro(), game.id, &conn).unwrap();
+        update_description_(doro(), game.id, "ours".to_owned(), &conn).unwrap();
+    }
+
+    #[test]
+    fn friendships_and_blocks() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "insert into user (id, username) values (1, 'rolf'), (2, 'doro'), (3, 'mara')",
+        )
+        .unwrap();
+        let name = |name: &str| name.to_owned();
+        let friend_names = |username: &str, conn: &Connection| -> Vec<String> {
+            friends_by_user_(username, conn)
+                .unwrap()
+                .into_iter()
+                .map(|user| user.username)
+                .collect()
+        };
+
+        // Asking back accepts the pending request.
+        request_friendship_(name("rolf"), name("doro"), &conn).unwrap();
+        assert!(!are_friends_(1, 2, &conn).unwrap());
+        request_friendship_(name("doro"), name("rolf"), &conn).unwrap();
+        assert!(are_friends_(1, 2, &conn).unwrap());
+        assert_eq!(friend_names("rolf", &conn), vec![name("doro")]);
+
+        block_user_(name("doro"), name("rolf"), &conn).unwrap();
+        assert!(!are_friends_(1, 2, &conn).unwrap());
+        assert!(friend_names("rolf", &conn).is_empty());
+        for (username, friend) in &[("rolf", "doro"), ("doro", "rolf")] {
+            match request_friendship_(name(username), name(friend), &conn) {
+                Err(DbError::Blocked) => {}
+                _ => panic!("Blocked users can't ask for a friendship."),
+            }
+        }
+
+        // Doro blocked rolf, so rolf can't invite doro. Mara is not a friend
+        // of rolf and can only be invited to games that are not friends only.
+        let create = dto::GameCreate {
+            description: "friends".to_owned(),
+            visibility: dto::Visibility::default(),
+            rules: GameRules::default(),
+        };
+        let game = create_game_(name("rolf"), create, &conn).unwrap();
+        let invitation = |id, username: &str| dto::Member {
+            id,
+            username: name(username),
+            role: dto::MemberRole::Watcher,
+            ready_state: dto::ReadyState::Invited,
+        };
+        match update_member_(name("rolf"), game.id, invitation(2, "doro"), false, &conn) {
+            Err(DbError::Blocked) => {}
+            _ => panic!("Users who blocked someone can't be invited by them."),
+        }
+        match update_member_(name("rolf"), game.id, invitation(3, "mara"), true, &conn) {
+            Err(DbError::NotAFriend) => {}
+            _ => panic!("Only friends can be invited."),
+        }
+        update_member_(name("rolf"), game.id, invitation(3, "mara"), false, &conn).unwrap();
+    }
+
+    #[test]
+    fn start_when_the_seats_are_freed() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::migration::migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "insert into user (id, username) values (1, 'rolf'), (2, 'doro'), (3, 'mara')",
+        )
+        .unwrap();
+        let create = dto::GameCreate {
+            description: "seats".to_owned(),
+            visibility: dto::Visibility::default(),
+            rules: GameRules::default(),
+        };
+        let status = |game_id, conn: &Connection| game_status_(game_id, conn).unwrap();
+
+        // Mara takes the second seat as well, so doro can't start the game.
+        let mut game_ids = Vec::new();
+        for _ in 0..2 {
+            let game = create_game_("rolf".to_owned(), create.clone(), &conn).unwrap();
+            conn.execute(
+                "insert into game_member (user, game, role, ready_state)
+                 values (2, ?1, 2, 2), (3, ?1, 2, 2)",
+                params![game.id],
+            )
+            .unwrap();
+            set_ready_("rolf".to_owned(), game.id, true, &conn).unwrap();
+            set_ready_("doro".to_owned(), game.id, true, &conn).unwrap();
+            assert_eq!(status(game.id, &conn), Some(dto::GameStatus::Setup));
+            game_ids.push(game.id);
+        }
+
+        leave_game_("mara".to_owned(), game_ids[0], &conn).unwrap();
+        assert_eq!(status(game_ids[0], &conn), Some(dto::GameStatus::Running));
+
+        let watcher = dto::Member {
+            id: 3,
+            username: "mara".to_owned(),
+            role: dto::MemberRole::Watcher,
+            ready_state: dto::ReadyState::Accepted,
+        };
+        update_member_("rolf".to_owned(), game_ids[1], watcher, false, &conn).unwrap();
+        assert_eq!(status(game_ids[1], &conn), Some(dto::GameStatus::Running));
+    }
+
+    #[test]
+    fn roll_back_failed_mutations() {
+        let manager = r2d2_sqlite::SqliteConnectionManager::memory();
+        let pool = Pool::builder().max_size(1).build(manager).unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch("create table game (id integer primary key, description text);")
+            .unwrap();
+
+        let result: Result<(), DbError> = in_transaction(&pool, |conn| {
+            conn.execute("insert into game (description) values ('new')", params![])?;
+            Err(DbError::GameNotFound)
+        });
+        assert!(result.is_err());
+        in_transaction(&pool, |conn| {
+            conn.execute("insert into game (description) values ('kept')", params![])?;
+            Ok(())
+        })
+        .unwrap();
+
+        let conn = pool.get().unwrap();
+        let mut stmt = conn.prepare("select description from game").unwrap();
+        let descriptions: Vec<String> = stmt
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(descriptions, vec!["kept".to_owned()]);
     }
 }
@@ -0,0 +1,110 @@
+//! Administration
+//!
+//! These functions are used by the command line interface. They run
+//! synchronously and skip the checks that protect games from their users,
+//! so they must never be reachable from the web api.
+
+use super::*;
+
+pub fn create_user(
+    username: &str,
+    password: &str,
+    hashing_iteration_count: u32,
+    pool: &Pool,
+) -> Result<(), DbError> {
+    validate_username(username)?;
+    let password_hash = hash_new_password(password, hashing_iteration_count)?;
+    in_transaction(pool, |conn| register_user_(username, &password_hash, conn))
+}
+
+/// Deletes the user. Their memberships, friendships and blocks are deleted
+/// by the foreign keys, their games remain.
+pub fn delete_user(username: &str, pool: &Pool) -> Result<(), DbError> {
+    in_transaction(pool, |conn| {
+        let user_id = user_id_(username.to_owned(), conn)?;
+        conn.execute("delete from user where id = ?1", params![user_id])?;
+        Ok(())
+    })
+}
+
+/// Sets a new password without knowing the old one.
+pub fn reset_password(
+    username: &str,
+    password: &str,
+    hashing_iteration_count: u32,
+    pool: &Pool,
+) -> Result<(), DbError> {
+    let password_hash = hash_new_password(password, hashing_iteration_count)?;
+    in_transaction(pool, |conn| {
+        user_id_(username.to_owned(), conn)?;
+        set_password_hash_(username, &password_hash, conn)
+    })
+}
+
+pub fn users(pool: &Pool) -> Result<Vec<dto::UserInfo>, DbError> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare("select id, username from user order by id")?;
+
+    let user_iter = stmt.query_map(params![], |row| {
+        Ok(dto::UserInfo {
+            id: row.get(0)?,
+            username: row.get(1)?,
+        })
+    })?;
+
+    let mut users = Vec::new();
+    for user in user_iter {
+        users.push(user?);
+    }
+    Ok(users)
+}
+
+pub fn games(pool: &Pool) -> Result<Vec<dto::GameHeader>, DbError> {
+    games_where_("1 = 1", params![], &*pool.get()?)
+}
+
+/// Everything that is stored about a game.
+pub struct GameDetails {
+    pub header: dto::GameHeader,
+    pub history: Vec<GameAction>,
+    pub state: GameState,
+}
+
+pub fn game(game_id: i64, pool: &Pool) -> Result<GameDetails, DbError> {
+    let conn = pool.get()?;
+    let header = game_(game_id, &conn)?.ok_or(DbError::GameNotFound)?;
+    let history = game_history_(game_id, &conn)?.ok_or(DbError::GameNotFound)?;
+    let state = game::replay(initial_state_(game_id, &conn)?, &history)?;
+    Ok(GameDetails {
+        header,
+        history,
+        state,
+    })
+}
+
+/// Ends the game, the state is kept as it is.
+pub fn finish_game(game_id: i64, pool: &Pool) -> Result<(), DbError> {
+    in_transaction(pool, |conn| {
+        let updated = conn.execute(
+            "update game set status = ?1 where id = ?2",
+            params![dto::GameStatus::Finished, game_id],
+        )?;
+        if updated == 0 {
+            return Err(DbError::GameNotFound);
+        }
+        Ok(())
+    })
+}
+
+/// Deletes the game with its members and actions.
+pub fn delete_game(game_id: i64, pool: &Pool) -> Result<(), DbError> {
+    in_transaction(pool, |conn| {
+        if !game_exists_(game_id, conn)? {
+            return Err(DbError::GameNotFound);
+        }
+        conn.execute("delete from game_data where game = ?1", params![game_id])?;
+        conn.execute("delete from game_member where game = ?1", params![game_id])?;
+        conn.execute("delete from game where id = ?1", params![game_id])?;
+        Ok(())
+    })
+}
@@ -1,11 +1,15 @@
 /// This file defines data transfer objects.
+use super::variant::{GameAction, GameRules, GameState};
 use serde::{Deserialize, Serialize};
 
 /// The information required to display a game in an overview table.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GameHeader {
     pub id: i64,
     pub description: String,
+    pub status: GameStatus,
+    pub visibility: Visibility,
+    pub rules: GameRules,
     pub members: Vec<Member>,
 }
 
@@ -15,6 +19,12 @@ pub struct GameHeader {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct GameCreate {
     pub description: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// The rules also select the game type. Nim with the classic rules is
+    /// played if no rules are given.
+    #[serde(default)]
+    pub rules: GameRules,
 }
 
 /// Publicly available information about a user.
@@ -24,6 +34,32 @@ pub struct UserInfo {
     pub username: String,
 }
 
+/// Pending friend requests of a user, in both directions.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FriendRequests {
+    /// Users that asked to become friends with the user.
+    pub incoming: Vec<UserInfo>,
+    /// Users that the user asked to become friends with.
+    pub outgoing: Vec<UserInfo>,
+}
+
+/// This type can be send to change the friends of the logged in user. The
+/// other user is identified by their username.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FriendMessage {
+    /// Asks the other user to become friends. If they already asked the
+    /// user, the friendship is accepted instead.
+    Request(String),
+    /// Accepts a friend request of the other user.
+    Accept(String),
+    /// Ends a friendship, or declines or withdraws a friend request.
+    Remove(String),
+    /// Ends the friendship and stops the other user from sending friend
+    /// requests or invitations to the user.
+    Block(String),
+    Unblock(String),
+}
+
 /// A game can have several members with different roles.
 /// TODO: Rename to GameMember
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,17 +67,31 @@ pub struct Member {
     pub id: i64,
     pub username: String,
     pub role: MemberRole,
-    pub accepted: bool,
+    pub ready_state: ReadyState,
 }
 
 /// The integers should be server only, the tags should be send to the client.
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MemberRole {
     WhitePlayer = 1,
     BlackPlayer = 2,
     Watcher = 3,
 }
 
+impl MemberRole {
+    /// The number of roles that hold a player seat.
+    pub const PLAYER_SEATS: u8 = 2;
+
+    /// The index of the player in the game rules, if the role holds a seat.
+    pub fn player_index(self) -> Option<u8> {
+        match self {
+            MemberRole::WhitePlayer => Some(0),
+            MemberRole::BlackPlayer => Some(1),
+            MemberRole::Watcher => None,
+        }
+    }
+}
+
 /// This implementation is important for database mapping.
 impl rusqlite::types::FromSql for MemberRole {
     fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
@@ -59,7 +109,7 @@ impl rusqlite::types::FromSql for MemberRole {
 }
 
 impl rusqlite::types::ToSql for MemberRole {
-    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput> {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
         use rusqlite::types::ToSqlOutput::Owned;
         use rusqlite::types::Value::Integer;
         Ok(Owned(Integer(*self as i64)))
@@ -72,11 +122,104 @@ impl rusqlite::types::ToSql for MemberRole {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SetupMessage {
     SetDescription(String),
+    /// Changes who is allowed to see the game, see `Visibility`.
+    SetVisibility(Visibility),
     UpdateMember(Member),
+    /// The user accepts their invitation to the game.
+    AcceptInvitation,
+    /// The user declines their invitation and is removed from the game.
+    DeclineInvitation,
+    /// A player declares if they are ready to start the game.
+    SetReady(bool),
+    /// The user leaves the game. Players can not leave a running game.
+    LeaveGame,
+}
+
+/// This type is pushed to the client over the websocket whenever a game the
+/// user is a member of changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PushMessage {
+    /// A new game was created. This is only send to the creator.
+    GameCreated(GameHeader),
+    DescriptionChanged {
+        game: i64,
+        description: String,
+    },
+    MemberChanged {
+        game: i64,
+        member: Member,
+    },
+    /// The game changed in a way that is not covered by the messages above,
+    /// e.g. a member left or the game started.
+    GameChanged(GameHeader),
+    /// An action was executed, this contains the new state of the game.
+    StateChanged {
+        game: i64,
+        state: GameState,
+    },
+}
+
+/// The legal actions of the current player in a game, so the client can
+/// highlight what can be selected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegalActions {
+    /// The number of legal actions, zero if the game is over or not running.
+    /// It is sent as a string, see `large_count`.
+    #[serde(with = "large_count")]
+    pub count: u128,
+    /// All legal actions, unless there are too many of them.
+    pub actions: Option<Vec<GameAction>>,
+    /// The tokens, or the heaps in a game of `HeapNim`, that are part of at
+    /// least one legal action.
+    pub selectable: Vec<usize>,
+}
+
+impl LegalActions {
+    /// The legal actions of a game that is not running, nobody may act.
+    pub fn none() -> Self {
+        LegalActions {
+            count: 0,
+            actions: Some(Vec::new()),
+            selectable: Vec::new(),
+        }
+    }
+}
+
+/// Action counts can exceed 2^53, above which JSON numbers lose precision in
+/// JavaScript. They are sent as decimal strings instead.
+mod large_count {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(count: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(count)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// The outcome of a position if both players play perfectly, see `solver`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Analysis {
+    /// The player who wins with perfect play. Once the game is over, this is
+    /// the winner of the game.
+    pub winner: u8,
+    /// Whether the player to move wins. The winner of a finished game is its
+    /// current player, so this is always true once the game is over.
+    pub current_player_wins: bool,
+    /// The number of actions of the current player that keep the win, zero
+    /// if they lose. It is sent as a string, see `large_count`.
+    #[serde(with = "large_count")]
+    pub winning_action_count: u128,
+    /// All winning actions, unless there are too many of them.
+    pub winning_actions: Option<Vec<GameAction>>,
 }
 
 /// The integers should be server only, the tags should be send to the client.
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ReadyState {
     /// The user has been invited to the game but has not accepted the request.
     Invited = 1,
@@ -104,7 +247,86 @@ impl rusqlite::types::FromSql for ReadyState {
 }
 
 impl rusqlite::types::ToSql for ReadyState {
-    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput> {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        use rusqlite::types::ToSqlOutput::Owned;
+        use rusqlite::types::Value::Integer;
+        Ok(Owned(Integer(*self as i64)))
+    }
+}
+
+/// The integers should be server only, the tags should be send to the client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameStatus {
+    /// The members are invited and the seats are assigned.
+    Setup = 1,
+    /// All players are ready and the game is being played.
+    Running = 2,
+    /// The game is over, the state does not change anymore.
+    Finished = 3,
+}
+
+/// This implementation is important for database mapping.
+impl rusqlite::types::FromSql for GameStatus {
+    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        use rusqlite::types::FromSqlError::{InvalidType, OutOfRange};
+        use rusqlite::types::ValueRef::Integer;
+        use GameStatus::*;
+        match value {
+            Integer(1) => Ok(Setup),
+            Integer(2) => Ok(Running),
+            Integer(3) => Ok(Finished),
+            Integer(n) => Err(OutOfRange(n)),
+            _ => Err(InvalidType),
+        }
+    }
+}
+
+impl rusqlite::types::ToSql for GameStatus {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        use rusqlite::types::ToSqlOutput::Owned;
+        use rusqlite::types::Value::Integer;
+        Ok(Owned(Integer(*self as i64)))
+    }
+}
+
+/// Decides who can see a game. Members can always see their games.
+/// The integers should be server only, the tags should be send to the client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Visibility {
+    /// Everyone can see the game and it is listed in the public game list.
+    Public = 1,
+    /// Only members of the game can see it.
+    MembersOnly = 2,
+    /// Everyone who knows the id of the game can see it, but it is not
+    /// listed anywhere.
+    Unlisted = 3,
+}
+
+/// New games are private unless the creator decides otherwise.
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::MembersOnly
+    }
+}
+
+/// This implementation is important for database mapping.
+impl rusqlite::types::FromSql for Visibility {
+    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        use rusqlite::types::FromSqlError::{InvalidType, OutOfRange};
+        use rusqlite::types::ValueRef::Integer;
+        use Visibility::*;
+        match value {
+            Integer(1) => Ok(Public),
+            Integer(2) => Ok(MembersOnly),
+            Integer(3) => Ok(Unlisted),
+            Integer(n) => Err(OutOfRange(n)),
+            _ => Err(InvalidType),
+        }
+    }
+}
+
+impl rusqlite::types::ToSql for Visibility {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
         use rusqlite::types::ToSqlOutput::Owned;
         use rusqlite::types::Value::Integer;
         Ok(Owned(Integer(*self as i64)))
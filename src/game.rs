@@ -0,0 +1,104 @@
+//! Interface between the server and the rules of a game.
+//!
+//! The server only talks to the rules through the `Game` trait. This way the
+//! game lifecycle, storage and api do not depend on a specific rule set.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// A turn based game. The implementing type is the state of the game, it is
+/// modified by executing actions of the current player.
+///
+/// Players are identified by their index, starting at zero.
+pub trait Game: Clone + Serialize + DeserializeOwned {
+    /// A move that a player can make.
+    type Action: Clone + Serialize + DeserializeOwned;
+    /// The reason why an action was rejected.
+    type Error: Clone + Debug + Serialize;
+
+    /// Applies the action of the current player. If the action is not legal,
+    /// an error is returned and the state stays unchanged.
+    fn execute(&mut self, action: &Self::Action) -> Result<(), Self::Error>;
+
+    /// The player that is expected to act next.
+    fn current_player(&self) -> u8;
+
+    /// The player that won the game, if the game is over.
+    fn winner(&self) -> Option<u8>;
+
+    /// The number of players taking part in the game.
+    fn player_count(&self) -> u8;
+
+    fn is_over(&self) -> bool {
+        self.winner().is_some()
+    }
+}
+
+/// Decides who wins once no legal move is left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlayConvention {
+    /// The player who made the last move wins.
+    Normal,
+    /// The player who made the last move loses. The player who can't move
+    /// wins, for two players this is the opponent of the last player.
+    Misere,
+}
+
+/// Whose turn it is and who won, for games that are over as soon as the
+/// current player can't move. The games embed this in their state. By
+/// default, the first player starts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurnOrder {
+    current_player: u8,
+    winner: Option<u8>,
+}
+
+impl TurnOrder {
+    pub fn current_player(&self) -> u8 {
+        self.current_player
+    }
+
+    pub fn winner(&self) -> Option<u8> {
+        self.winner
+    }
+
+    /// Passes the turn to the next player after a move. If that player can't
+    /// move, the game is over.
+    pub fn next_turn(&mut self, player_count: u8, convention: PlayConvention, can_move: bool) {
+        self.current_player = (self.current_player + 1) % player_count;
+        self.check_game_over(player_count, convention, can_move);
+    }
+
+    /// Ends the game if the current player can't move. The winner becomes
+    /// the current player.
+    pub fn check_game_over(
+        &mut self,
+        player_count: u8,
+        convention: PlayConvention,
+        can_move: bool,
+    ) {
+        if can_move {
+            return;
+        }
+        let winner = match convention {
+            // The previous player made the last move.
+            PlayConvention::Normal => (self.current_player + player_count - 1) % player_count,
+            PlayConvention::Misere => self.current_player,
+        };
+        self.winner = Some(winner);
+        self.current_player = winner;
+    }
+}
+
+/// Rebuilds a state by executing the actions in order, starting from the
+/// initial state. This fails if any of the actions is not legal.
+pub fn replay<G: Game>(initial_state: G, actions: &[G::Action]) -> Result<G, failure::Error> {
+    let mut state = initial_state;
+    for (index, action) in actions.iter().enumerate() {
+        state.execute(action).map_err(|error| {
+            failure::format_err!("Action {} can not be replayed: {:?}", index, error)
+        })?;
+    }
+    Ok(state)
+}
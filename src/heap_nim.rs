@@ -0,0 +1,256 @@
+//! Implementation of the classical game of Nim
+//!
+//! The tokens are split into several heaps. In each move, a player takes any
+//! number of tokens from exactly one heap. The turns and the winner follow
+//! the same conventions as the single heap game in `nim`.
+
+use super::game::{Game, PlayConvention, TurnOrder};
+use super::nim::{RulesError, MAX_TOKEN_COUNT};
+use serde::{Deserialize, Serialize};
+
+/// The most heaps a game can start with.
+pub const MAX_HEAP_COUNT: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeapNim {
+    rules: HeapNimRules,
+    /// The number of tokens left on each heap.
+    heaps: Vec<usize>,
+    #[serde(flatten)]
+    turns: TurnOrder,
+}
+
+/// The rule set of a game. Missing fields take their value from the default,
+/// which are three heaps of 3, 4 and 5 tokens where whoever takes the last
+/// token wins.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeapNimRules {
+    /// The number of tokens on each heap when the game starts.
+    pub heaps: Vec<usize>,
+    pub convention: PlayConvention,
+    pub player_count: u8,
+}
+
+impl Default for HeapNimRules {
+    fn default() -> Self {
+        HeapNimRules {
+            heaps: vec![3, 4, 5],
+            convention: PlayConvention::Normal,
+            player_count: 2,
+        }
+    }
+}
+
+impl HeapNimRules {
+    /// Checks that a game with these rules can be played.
+    pub fn validate(&self) -> Result<(), RulesError> {
+        if self.heaps.is_empty() || self.heaps.len() > MAX_HEAP_COUNT {
+            Err(RulesError::HeapCount)
+        } else if self
+            .heaps
+            .iter()
+            .any(|heap| *heap == 0 || *heap > MAX_TOKEN_COUNT)
+        {
+            Err(RulesError::TokenCount)
+        } else if self.player_count < 2 {
+            Err(RulesError::PlayerCount)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeapNimAction {
+    pub heap: usize,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HeapNimError {
+    HeapOutOfBounds,
+    /// A move must take at least one token.
+    NoTokensTaken,
+    /// The heap has fewer tokens than the move takes.
+    HeapTooSmall,
+    GameAlreadyOver,
+}
+
+impl HeapNim {
+    /// The rules must be valid, see `HeapNimRules::validate`.
+    pub fn new(rules: HeapNimRules) -> Self {
+        HeapNim {
+            heaps: rules.heaps.clone(),
+            rules,
+            turns: TurnOrder::default(),
+        }
+    }
+
+    pub fn rules(&self) -> &HeapNimRules {
+        &self.rules
+    }
+
+    /// The number of tokens left on each heap.
+    pub fn heaps(&self) -> &[usize] {
+        &self.heaps
+    }
+
+    fn has_legal_action(&self) -> bool {
+        self.heaps.iter().any(|heap| *heap > 0)
+    }
+
+    /// Every legal action of the current player, ordered by heap and count.
+    pub fn legal_actions(&self) -> Box<dyn Iterator<Item = HeapNimAction>> {
+        if self.turns.winner().is_some() {
+            return Box::new(std::iter::empty());
+        }
+        let heaps = self.heaps.clone();
+        Box::new(
+            heaps.into_iter().enumerate().flat_map(|(heap, size)| {
+                (1..=size).map(move |count| HeapNimAction { heap, count })
+            }),
+        )
+    }
+
+    /// A heap allows one action for each of its tokens.
+    pub fn legal_action_count(&self) -> u128 {
+        if self.turns.winner().is_some() {
+            return 0;
+        }
+        self.heaps.iter().map(|heap| *heap as u128).sum()
+    }
+
+    /// The heaps that still have tokens, in order.
+    pub fn selectable_heaps(&self) -> Vec<usize> {
+        if self.turns.winner().is_some() {
+            return Vec::new();
+        }
+        (0..self.heaps.len())
+            .filter(|heap| self.heaps[*heap] > 0)
+            .collect()
+    }
+}
+
+impl Game for HeapNim {
+    type Action = HeapNimAction;
+    type Error = HeapNimError;
+
+    fn execute(&mut self, action: &HeapNimAction) -> Result<(), HeapNimError> {
+        use HeapNimError::*;
+        if self.turns.winner().is_some() {
+            Err(GameAlreadyOver)
+        } else if action.heap >= self.heaps.len() {
+            Err(HeapOutOfBounds)
+        } else if action.count == 0 {
+            Err(NoTokensTaken)
+        } else if action.count > self.heaps[action.heap] {
+            Err(HeapTooSmall)
+        } else {
+            self.heaps[action.heap] -= action.count;
+
+            // Proceed to the next player, who may be unable to move.
+            let can_move = self.has_legal_action();
+            self.turns
+                .next_turn(self.rules.player_count, self.rules.convention, can_move);
+
+            Ok(())
+        }
+    }
+
+    fn current_player(&self) -> u8 {
+        self.turns.current_player()
+    }
+
+    fn winner(&self) -> Option<u8> {
+        self.turns.winner()
+    }
+
+    fn player_count(&self) -> u8 {
+        self.rules.player_count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn take(heap: usize, count: usize) -> HeapNimAction {
+        HeapNimAction { heap, count }
+    }
+
+    #[test]
+    fn take_from_heaps() {
+        let mut game = HeapNim::new(HeapNimRules::default());
+        game.execute(&take(2, 5)).unwrap();
+        assert_eq!(game.heaps, vec![3, 4, 0]);
+        assert_eq!(game.current_player(), 1);
+        game.execute(&take(1, 4)).unwrap();
+        game.execute(&take(0, 2)).unwrap();
+        assert_eq!(game.winner(), None);
+        game.execute(&take(0, 1)).unwrap();
+        assert_eq!(game.winner(), Some(1));
+        assert!(game.is_over());
+        assert_eq!(
+            game.execute(&take(0, 1)),
+            Err(HeapNimError::GameAlreadyOver)
+        );
+    }
+
+    #[test]
+    fn reject_illegal_actions() {
+        let mut game = HeapNim::new(HeapNimRules::default());
+        let initial = game.clone();
+        assert_eq!(
+            game.execute(&take(3, 1)),
+            Err(HeapNimError::HeapOutOfBounds)
+        );
+        assert_eq!(game.execute(&take(0, 0)), Err(HeapNimError::NoTokensTaken));
+        assert_eq!(game.execute(&take(0, 4)), Err(HeapNimError::HeapTooSmall));
+        assert_eq!(game, initial);
+    }
+
+    #[test]
+    fn enumerate_legal_actions() {
+        let mut game = HeapNim::new(HeapNimRules {
+            heaps: vec![2, 1],
+            ..HeapNimRules::default()
+        });
+        let actions: Vec<HeapNimAction> = game.legal_actions().collect();
+        assert_eq!(actions, vec![take(0, 1), take(0, 2), take(1, 1)]);
+        assert_eq!(game.legal_action_count(), 3);
+
+        game.execute(&take(0, 2)).unwrap();
+        assert_eq!(game.selectable_heaps(), vec![1]);
+        game.execute(&take(1, 1)).unwrap();
+        assert_eq!(game.legal_action_count(), 0);
+        assert_eq!(game.legal_actions().count(), 0);
+    }
+
+    #[test]
+    fn misere_play() {
+        let mut game = HeapNim::new(HeapNimRules {
+            heaps: vec![2, 1],
+            convention: PlayConvention::Misere,
+            ..HeapNimRules::default()
+        });
+        game.execute(&take(0, 2)).unwrap();
+        game.execute(&take(1, 1)).unwrap();
+        // The second player took the last token and loses.
+        assert_eq!(game.winner(), Some(0));
+    }
+
+    #[test]
+    fn validate_rules() {
+        assert_eq!(HeapNimRules::default().validate(), Ok(()));
+        let no_heaps = HeapNimRules {
+            heaps: vec![],
+            ..HeapNimRules::default()
+        };
+        assert_eq!(no_heaps.validate(), Err(RulesError::HeapCount));
+        let empty_heap = HeapNimRules {
+            heaps: vec![3, 0],
+            ..HeapNimRules::default()
+        };
+        assert_eq!(empty_heap.validate(), Err(RulesError::TokenCount));
+    }
+}
@@ -1,5 +1,7 @@
 use actix_files::{Files, NamedFile};
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::dev::Service;
+use actix_web::error::BlockingError;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, ResponseError};
 
 use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
 use std::collections::HashMap;
@@ -11,19 +13,46 @@ use futures::Future;
 
 use serde::{Deserialize, Serialize};
 
-use r2d2_sqlite;
-use r2d2_sqlite::SqliteConnectionManager;
-
+mod cli;
+mod config;
+use config::{Config, GameConfig, SecurityConfig, TlsConfig};
+mod csrf;
 mod db;
 use db::Pool;
 mod dto;
+mod game;
+mod heap_nim;
+mod migration;
 mod nim;
+mod socket;
+use socket::SocketHub;
+mod solver;
+mod variant;
 
 /// Launches our demo server.
 pub fn main() {
+    let arguments = exit_on_error(cli::parse_arguments(std::env::args().skip(1)));
+    if arguments.command == cli::Command::GenerateSecret {
+        exit_on_error(config::generate_config(&arguments.config_path));
+        println!(
+            "Created the configuration file {}.",
+            arguments.config_path.display()
+        );
+        return;
+    }
+
     // Read configuration file. This contains secrets and variable parameters.
-    let config = Config::read_configuration().unwrap();
+    let config = exit_on_error(Config::load(&arguments.config_path));
+
+    // All other commands are administration tasks.
+    if arguments.command != cli::Command::Serve {
+        exit_on_error(cli::run(arguments.command, &config));
+        return;
+    }
+
     let server_address = config.server_address();
+    // The certificate is read once, so a broken one is reported on startup.
+    let tls = exit_on_error(config.tls.as_ref().map(TlsConfig::load).transpose());
 
     // Initialize shared server state (Not used right now, carried allong from the tutorial for reference.)
     let counter = web::Data::new(AppStateWithCounter {
@@ -31,74 +60,138 @@ pub fn main() {
     });
 
     // Start N db executor actors (N = number of cores avail)
-    let manager = SqliteConnectionManager::file("./home/nim.db");
-    let pool = Pool::new(manager).unwrap();
+    let database = config.database_path.display();
+    let pool = exit_on_error(
+        db::open(&config.database_path)
+            .map_err(|error| format!("Could not open the database {}: {}", database, error)),
+    );
+
+    // The schema is created and updated on startup.
+    let applied = exit_on_error(
+        pool.get()
+            .map_err(failure::Error::from)
+            .and_then(|mut conn| migration::migrate(&mut conn))
+            .map_err(|error| format!("Could not migrate the database {}: {}", database, error)),
+    );
+    if applied > 0 {
+        println!("Applied {} database migrations.", applied);
+    }
 
-    HttpServer::new(move || {
+    // The security settings are required to hash passwords.
+    let security = web::Data::new(config.security.clone());
+    let game_config = web::Data::new(config.game.clone());
+    let token_cookie = web::Data::new(csrf::TokenCookie::new(&config));
+
+    // All open websocket connections, shared between the server threads.
+    let socket_hub = web::Data::new(SocketHub::new());
+
+    let server = HttpServer::new(move || {
         App::new()
             .data(pool.clone())
-            .wrap(IdentityService::new(
-                // <- create identity middleware
-                CookieIdentityPolicy::new(config.security.identity_cookie_secret.as_bytes()) // <- create cookie identity policy
-                    .name("auth-cookie")
-                    .secure(false),
-            ))
+            // Requests that change something must carry the CSRF token.
+            .wrap_fn(|req, srv| match csrf::verify(&req) {
+                Ok(()) => futures::future::Either::A(srv.call(req)),
+                Err(error) => futures::future::Either::B(futures::future::err(error.into())),
+            })
+            .wrap(IdentityService::new(identity_policy(&config))) // <- create identity middleware
             // Register data that is shared between the server threads.
             // Currently this is only some dummy information to mention the concept in the code.
             .register_data(counter.clone()) // <- register the created data
+            .register_data(socket_hub.clone())
+            .register_data(security.clone())
+            .register_data(game_config.clone())
+            .register_data(token_cookie.clone())
             .route("/count", web::get().to(count_page))
             // We use the actix-files crate to serve static frontend content. Note that we use
             // .show_files_listing() for development which is generally not a good idea for production.
-            .service(Files::new("/static", "./frontend/static").show_files_listing())
+            .service(Files::new("/static", &config.static_directory).show_files_listing())
+            .route("/api/socket", web::get().to(push_socket))
             .route("/api/identity", web::get().to(identity))
             .route("/api/login", web::post().to_async(login))
-            .route("/api/logout", web::get().to(logout))
+            .route("/api/register", web::post().to_async(register))
+            .route("/api/logout", web::post().to(logout))
             .route("/api/game/create", web::post().to_async(create_game))
             .route("/api/game/list", web::get().to_async(list_games))
+            .route("/api/game/public", web::get().to_async(list_public_games))
+            .route(
+                "/api/game/invitations",
+                web::get().to_async(list_invitations),
+            )
             .route("/api/game/{id}", web::get().to_async(game_details))
             .route("/api/game/{id}/setup", web::post().to_async(game_setup))
+            .route(
+                "/api/game/{id}/accept",
+                web::post().to_async(accept_invitation),
+            )
+            .route(
+                "/api/game/{id}/decline",
+                web::post().to_async(decline_invitation),
+            )
+            .route("/api/game/{id}/leave", web::post().to_async(leave_game))
+            .route("/api/game/{id}/state", web::get().to_async(game_state))
+            .route("/api/game/{id}/history", web::get().to_async(game_history))
+            .route(
+                "/api/game/{id}/legal-actions",
+                web::get().to_async(legal_actions),
+            )
+            .route(
+                "/api/game/{id}/analysis",
+                web::get().to_async(game_analysis),
+            )
+            .route("/api/game/{id}/action", web::post().to_async(game_action))
             .route("/api/dummy", web::get().to_async(dummy_example))
             .route("/api/user/friends", web::get().to_async(friends_list))
+            .route("/api/user/friends", web::post().to_async(change_friends))
+            .route(
+                "/api/user/friends/requests",
+                web::get().to_async(friend_requests),
+            )
+            .route("/api/user/blocked", web::get().to_async(blocked_users))
+            .route("/api/user/password", web::post().to_async(change_password))
             // Serve the index page for all routes that do not match any earlier route.
             // We do not want this to happen to /api/.. routes, so we return a 404 on those first.
             .route("/api", web::get().to(api_error_page))
             .route("/api/{tail:.*}", web::get().to(api_error_page))
             .route("favicon.ico", web::get().to(favicon))
             .route("/{tail:.*}", web::get().to(index_page))
-    })
-    .bind(server_address)
-    .unwrap()
-    .run()
-    .unwrap();
-}
+    });
 
-#[derive(Deserialize, Clone)]
-struct Config {
-    ip: String,
-    port: u16,
-    security: SecurityConfig,
+    let server = match tls {
+        Some(tls) => server.bind_rustls(server_address, tls),
+        None => server.bind(server_address),
+    };
+    exit_on_error(server).run().unwrap();
 }
 
-#[derive(Deserialize, Clone)]
-struct SecurityConfig {
-    identity_cookie_secret: String,
-    hashing_iteration_count: u32,
-}
+/// The cookie that stores the identity of a user, configured by `[cookie]`.
+fn identity_policy(config: &Config) -> CookieIdentityPolicy {
+    let same_site = match config.cookie.same_site {
+        config::SameSite::Strict => actix_web::cookie::SameSite::Strict,
+        config::SameSite::Lax => actix_web::cookie::SameSite::Lax,
+        config::SameSite::None => actix_web::cookie::SameSite::None,
+    };
 
-impl Config {
-    fn server_address(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+    let mut policy = CookieIdentityPolicy::new(config.security.identity_cookie_secret.as_bytes())
+        .name("auth-cookie")
+        .secure(config.secure_cookie())
+        .same_site(same_site);
+    if let Some(max_age) = config.cookie.max_age_seconds {
+        policy = policy.max_age(max_age);
     }
+    if let Some(domain) = &config.cookie.domain {
+        policy = policy.domain(domain.as_str());
+    }
+    policy
+}
 
-    fn read_configuration() -> std::io::Result<Self> {
-        use std::fs::File;
-        use std::io::prelude::*;
-
-        let mut file = File::open("./home/config.toml")?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-
-        Ok(toml::from_str(&contents).unwrap())
+/// Problems during startup are reported to the operator without a panic.
+fn exit_on_error<T, E: std::fmt::Display>(result: Result<T, E>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
     }
 }
 
@@ -143,6 +236,18 @@ struct LoginRequest {
     password: String,
 }
 
+#[derive(Deserialize)]
+struct RegisterRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordRequest {
+    old_password: String,
+    new_password: String,
+}
+
 #[derive(Serialize)]
 struct LoginStatusInfo {
     identity: Option<String>,
@@ -190,6 +295,109 @@ impl ComplicatedErrorResult {
     }
 }
 
+/// Errors from the database module are send to the client in the same format
+/// as all other api errors. Rule violations are reported as an
+/// "IllegalAction" with the specific reason as a parameter.
+impl ResponseError for db::DbError {
+    fn error_response(&self) -> HttpResponse {
+        use db::DbError::*;
+        let (mut response, error) = match self {
+            UnknownUser => (HttpResponse::NotFound(), "UnknownUser"),
+            GameNotFound => (HttpResponse::NotFound(), "GameNotFound"),
+            NotAMember => (HttpResponse::Forbidden(), "NotAMember"),
+            NotAPlayer => (HttpResponse::Forbidden(), "NotAPlayer"),
+            NotInvited => (HttpResponse::Conflict(), "NotInvited"),
+            GameAlreadyStarted => (HttpResponse::Conflict(), "GameAlreadyStarted"),
+            GameNotRunning => (HttpResponse::Conflict(), "GameNotRunning"),
+            GameNotFinished => (HttpResponse::Conflict(), "GameNotFinished"),
+            NotYourTurn => (HttpResponse::Conflict(), "NotYourTurn"),
+            InvalidUsername => (HttpResponse::UnprocessableEntity(), "InvalidUsername"),
+            InvalidPassword => (HttpResponse::UnprocessableEntity(), "InvalidPassword"),
+            UsernameTaken => (HttpResponse::Conflict(), "UsernameTaken"),
+            WrongPassword => (HttpResponse::Forbidden(), "WrongPassword"),
+            FriendIsYourself => (HttpResponse::UnprocessableEntity(), "FriendIsYourself"),
+            NoFriendRequest => (HttpResponse::Conflict(), "NoFriendRequest"),
+            NotAFriend => (HttpResponse::Forbidden(), "NotAFriend"),
+            Blocked => (HttpResponse::Forbidden(), "Blocked"),
+            IllegalAction(_) => (HttpResponse::UnprocessableEntity(), "IllegalAction"),
+            InvalidRules(_) => (HttpResponse::UnprocessableEntity(), "InvalidRules"),
+            Internal(error) => {
+                eprintln!("Internal error: {}", error);
+                (HttpResponse::InternalServerError(), "InternalServerError")
+            }
+        };
+
+        let mut result = ComplicatedErrorResult::new(error.to_owned());
+        if let IllegalAction(action_error) = self {
+            use heap_nim::HeapNimError;
+            use nim::NimError;
+            use variant::ActionError;
+            let reason = match action_error {
+                ActionError::WrongGameType => "WrongGameType",
+                ActionError::Nim(NimError::TokenAlreadyMissing) => "TokenAlreadyMissing",
+                ActionError::Nim(NimError::TokenOutOfBounds) => "TokenOutOfBounds",
+                ActionError::Nim(NimError::ToManyTokens) => "ToManyTokens",
+                ActionError::Nim(NimError::NotEnoughtTokens) => "NotEnoughtTokens",
+                ActionError::Nim(NimError::TokensNotContiguous) => "TokensNotContiguous",
+                ActionError::HeapNim(HeapNimError::HeapOutOfBounds) => "HeapOutOfBounds",
+                ActionError::HeapNim(HeapNimError::NoTokensTaken) => "NoTokensTaken",
+                ActionError::HeapNim(HeapNimError::HeapTooSmall) => "HeapTooSmall",
+                ActionError::Nim(NimError::GameAlreadyOver)
+                | ActionError::HeapNim(HeapNimError::GameAlreadyOver) => "GameAlreadyOver",
+            };
+            result = result.info("reason".to_owned(), reason.to_owned());
+        }
+        if let InvalidRules(rules_error) = self {
+            use nim::RulesError::*;
+            let reason = match rules_error {
+                TokenCount => "TokenCount",
+                TakeRange => "TakeRange",
+                HeapCount => "HeapCount",
+                PlayerCount => "PlayerCount",
+            };
+            result = result.info("reason".to_owned(), reason.to_owned());
+        }
+        response.json(result)
+    }
+
+    // The default implementation replaces the body with the display text.
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
+    }
+}
+
+impl ResponseError for csrf::CsrfError {
+    fn error_response(&self) -> HttpResponse {
+        let error = match self {
+            csrf::CsrfError::MissingToken => "MissingCsrfToken",
+            csrf::CsrfError::TokenMismatch => "CsrfTokenMismatch",
+            csrf::CsrfError::OriginMismatch => "OriginMismatch",
+        };
+        HttpResponse::Forbidden().json(ComplicatedErrorResult::new(error.to_owned()))
+    }
+
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
+    }
+}
+
+impl ResponseError for solver::SolverError {
+    fn error_response(&self) -> HttpResponse {
+        let reason = match self {
+            solver::SolverError::TooManyPlayers => "TooManyPlayers",
+            solver::SolverError::TooComplex => "TooComplex",
+        };
+        HttpResponse::UnprocessableEntity().json(
+            ComplicatedErrorResult::new("AnalysisUnavailable".to_owned())
+                .info("reason".to_owned(), reason.to_owned()),
+        )
+    }
+
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
+    }
+}
+
 fn identity(id: Identity) -> HttpResponse {
     HttpResponse::Ok().json(LoginStatusInfo {
         identity: id.identity(),
@@ -200,10 +408,16 @@ fn login(
     id: Identity,
     payload: web::Json<LoginRequest>,
     db: web::Data<Pool>,
+    security: web::Data<SecurityConfig>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
     let username = payload.username.clone();
 
-    let result = db::check_password(payload.username.clone(), payload.password.clone(), &db);
+    let result = db::check_password(
+        payload.username.clone(),
+        payload.password.clone(),
+        security.hashing_iteration_count,
+        &db,
+    );
 
     result
         .map_err(actix_web::Error::from)
@@ -222,6 +436,58 @@ fn login(
         })
 }
 
+/// Creates a new user and logs them in.
+fn register(
+    id: Identity,
+    payload: web::Json<RegisterRequest>,
+    db: web::Data<Pool>,
+    security: web::Data<SecurityConfig>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let payload = payload.into_inner();
+    let username = payload.username.clone();
+
+    db::register_user(
+        payload.username,
+        payload.password,
+        security.hashing_iteration_count,
+        &db,
+    )
+    .map_err(actix_web::Error::from)
+    .map(move |()| {
+        id.remember(username.clone());
+
+        HttpResponse::Ok().json(LoginStatusInfo {
+            identity: Some(username),
+        })
+    })
+}
+
+fn change_password(
+    id: Identity,
+    payload: web::Json<ChangePasswordRequest>,
+    db: web::Data<Pool>,
+    security: web::Data<SecurityConfig>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    if let Some(user) = id.identity() {
+        let payload = payload.into_inner();
+        Box::new(
+            db::change_password(
+                user,
+                payload.old_password,
+                payload.new_password,
+                security.hashing_iteration_count,
+                &db,
+            )
+            .map_err(actix_web::Error::from)
+            .map(|()| HttpResponse::Ok().json(())),
+        )
+    } else {
+        Box::new(futures::future::ok(
+            HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
+        ))
+    }
+}
+
 fn logout(id: Identity) -> HttpResponse {
     id.forget();
     identity(id)
@@ -230,41 +496,59 @@ fn logout(id: Identity) -> HttpResponse {
 #[derive(Template)]
 #[template(path = "index.askama", escape = "html")]
 struct HelloTemplate {
-    flags: LoginStatusInfo,
+    flags: IndexFlags,
+}
+
+/// Passed to elm when it starts.
+#[derive(Serialize)]
+struct IndexFlags {
+    identity: Option<String>,
+    /// The client sends this back with every request that changes something.
+    csrf_token: String,
 }
 
 /// Returns the index.html page. The file is created by askama and may contain
 /// information in the javascript that is passed via flags to elm.
-fn index_page(id: Identity) -> HttpResponse {
-    let info = LoginStatusInfo {
+fn index_page(
+    id: Identity,
+    req: HttpRequest,
+    token_cookie: web::Data<csrf::TokenCookie>,
+) -> HttpResponse {
+    let (csrf_token, cookie) = token_cookie.token(&req);
+    let info = IndexFlags {
         identity: id.identity(),
+        csrf_token,
     };
 
     let hello = HelloTemplate { flags: info };
 
     let s = hello.render().unwrap();
-    HttpResponse::Ok().content_type("text/html").body(s)
+    let mut response = HttpResponse::Ok();
+    if let Some(cookie) = cookie {
+        response.cookie(cookie);
+    }
+    response.content_type("text/html").body(s)
 }
 
-/// CRUD for games
+// CRUD for games
 
 fn create_game(
     id: Identity,
     create_info: web::Json<dto::GameCreate>,
     db: web::Data<Pool>,
+    hub: web::Data<SocketHub>,
 ) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
     if let Some(user) = id.identity() {
         let result = db::create_game(user, create_info.clone(), &db);
 
-        return Box::new(
-            result
-                .map_err(actix_web::Error::from)
-                .map(move |result| HttpResponse::Ok().json(result)),
-        );
+        Box::new(result.map_err(actix_web::Error::from).map(move |game| {
+            hub.notify(&game.members, &dto::PushMessage::GameCreated(game.clone()));
+            HttpResponse::Ok().json(game)
+        }))
     } else {
-        return Box::new(futures::future::ok(
+        Box::new(futures::future::ok(
             HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
-        ));
+        ))
     }
 }
 
@@ -277,40 +561,134 @@ fn list_games(
 
         // note that we need to box the result as the two different branches return
         // a different type.
-        return Box::new(result.map_err(actix_web::Error::from).map(move |games| {
+        Box::new(result.map_err(actix_web::Error::from).map(move |games| {
             let result = games;
             HttpResponse::Ok().json(result)
-        }));
+        }))
     } else {
-        return Box::new(futures::future::ok(
+        Box::new(futures::future::ok(
             HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
-        ));
+        ))
     }
 }
 
+/// Lists the games that the user was invited to, but did not accept yet.
+fn list_invitations(
+    id: Identity,
+    db: web::Data<Pool>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    if let Some(user) = id.identity() {
+        Box::new(
+            db::invitations_by_user(user, &db)
+                .map_err(actix_web::Error::from)
+                .map(move |games| HttpResponse::Ok().json(games)),
+        )
+    } else {
+        Box::new(futures::future::ok(
+            HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
+        ))
+    }
+}
+
+/// Lists all public games, this does not require a login.
+fn list_public_games(
+    db: web::Data<Pool>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    db::public_games(&db)
+        .map_err(actix_web::Error::from)
+        .map(move |games| HttpResponse::Ok().json(games))
+}
+
 fn game_details(
     path: web::Path<(i64,)>,
-    // id: Identity,
+    id: Identity,
     db: web::Data<Pool>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    let game = db::game(path.0, &db);
-
-    // TODO: Implement private games that are only visible to members.
+    let game = db::game(id.identity(), path.0, &db);
 
     game.map_err(actix_web::Error::from)
         .map(move |game| HttpResponse::Ok().json(game))
 }
 
+/// Lists the users that have a confirmed friendship with the user.
 fn friends_list(
-    // id: Identity,
+    id: Identity,
     db: web::Data<Pool>,
-) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    // TODO: Until we implement "friendship", all users are your friends.
-    let users = db::all_users(&db);
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    if let Some(user) = id.identity() {
+        Box::new(
+            db::friends_by_user(user, &db)
+                .map_err(actix_web::Error::from)
+                .map(move |users| HttpResponse::Ok().json(users)),
+        )
+    } else {
+        Box::new(futures::future::ok(
+            HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
+        ))
+    }
+}
 
-    users
-        .map_err(actix_web::Error::from)
-        .map(move |users| HttpResponse::Ok().json(users))
+fn friend_requests(
+    id: Identity,
+    db: web::Data<Pool>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    if let Some(user) = id.identity() {
+        Box::new(
+            db::friend_requests_by_user(user, &db)
+                .map_err(actix_web::Error::from)
+                .map(move |requests| HttpResponse::Ok().json(requests)),
+        )
+    } else {
+        Box::new(futures::future::ok(
+            HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
+        ))
+    }
+}
+
+fn blocked_users(
+    id: Identity,
+    db: web::Data<Pool>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    if let Some(user) = id.identity() {
+        Box::new(
+            db::blocked_users(user, &db)
+                .map_err(actix_web::Error::from)
+                .map(move |users| HttpResponse::Ok().json(users)),
+        )
+    } else {
+        Box::new(futures::future::ok(
+            HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
+        ))
+    }
+}
+
+fn change_friends(
+    message: web::Json<dto::FriendMessage>,
+    id: Identity,
+    db: web::Data<Pool>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    use dto::FriendMessage::*;
+
+    if let Some(user) = id.identity() {
+        type Update = Box<dyn Future<Item = (), Error = db::DbError>>;
+        let update: Update = match message.into_inner() {
+            Request(friend) => Box::new(db::request_friendship(user, friend, &db)),
+            Accept(friend) => Box::new(db::accept_friendship(user, friend, &db)),
+            Remove(friend) => Box::new(db::remove_friendship(user, friend, &db)),
+            Block(other) => Box::new(db::block_user(user, other, &db)),
+            Unblock(other) => Box::new(db::unblock_user(user, other, &db)),
+        };
+
+        Box::new(
+            update
+                .map_err(actix_web::Error::from)
+                .map(|()| HttpResponse::Ok().json(())),
+        )
+    } else {
+        Box::new(futures::future::ok(
+            HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
+        ))
+    }
 }
 
 fn game_setup(
@@ -318,27 +696,295 @@ fn game_setup(
     setup_message: web::Json<dto::SetupMessage>,
     id: Identity,
     db: web::Data<Pool>,
+    hub: web::Data<SocketHub>,
+    game_config: web::Data<GameConfig>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    apply_setup_message(path.0, setup_message.into_inner(), id, db, hub, game_config)
+}
+
+/// Shortcut for the "AcceptInvitation" setup message.
+fn accept_invitation(
+    path: web::Path<(i64,)>,
+    id: Identity,
+    db: web::Data<Pool>,
+    hub: web::Data<SocketHub>,
+    game_config: web::Data<GameConfig>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    apply_setup_message(
+        path.0,
+        dto::SetupMessage::AcceptInvitation,
+        id,
+        db,
+        hub,
+        game_config,
+    )
+}
+
+/// Shortcut for the "DeclineInvitation" setup message.
+fn decline_invitation(
+    path: web::Path<(i64,)>,
+    id: Identity,
+    db: web::Data<Pool>,
+    hub: web::Data<SocketHub>,
+    game_config: web::Data<GameConfig>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    apply_setup_message(
+        path.0,
+        dto::SetupMessage::DeclineInvitation,
+        id,
+        db,
+        hub,
+        game_config,
+    )
+}
+
+/// Shortcut for the "LeaveGame" setup message.
+fn leave_game(
+    path: web::Path<(i64,)>,
+    id: Identity,
+    db: web::Data<Pool>,
+    hub: web::Data<SocketHub>,
+    game_config: web::Data<GameConfig>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    apply_setup_message(
+        path.0,
+        dto::SetupMessage::LeaveGame,
+        id,
+        db,
+        hub,
+        game_config,
+    )
+}
+
+fn apply_setup_message(
+    game_id: i64,
+    setup_message: dto::SetupMessage,
+    id: Identity,
+    db: web::Data<Pool>,
+    hub: web::Data<SocketHub>,
+    game_config: web::Data<GameConfig>,
 ) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
     use dto::SetupMessage::*;
-    println!("{:?}", setup_message);
 
     if let Some(user) = id.identity() {
-        return match setup_message.clone() {
-            SetDescription(new_description) => Box::new(
-                db::update_description(user, path.0, new_description, &db)
-                    .map_err(actix_web::Error::from)
-                    .map(|()| HttpResponse::Ok().json(())),
-            ),
-            UpdateMember(member) => Box::new(
-                db::update_member(user, path.0, member, &db)
-                    .map_err(actix_web::Error::from)
-                    .map(|()| HttpResponse::Ok().json(())),
+        // Members who leave the game are not part of it afterwards, but their
+        // other sessions still need to know. The update only starts once the
+        // members are loaded.
+        type Members = Box<dyn Future<Item = Vec<dto::Member>, Error = db::DbError>>;
+        let members_before: Members = match setup_message {
+            DeclineInvitation | LeaveGame => Box::new(
+                db::game_unchecked(game_id, &db)
+                    .map(|game| game.map(|game| game.members).unwrap_or_default()),
             ),
+            _ => Box::new(futures::future::ok(Vec::new())),
         };
+
+        type Update = Box<dyn Future<Item = (), Error = db::DbError>>;
+        let update_db = db.clone();
+        let message = setup_message.clone();
+        let update = move || -> Update {
+            let db = update_db;
+            match message {
+                SetDescription(new_description) => {
+                    Box::new(db::update_description(user, game_id, new_description, &db))
+                }
+                SetVisibility(visibility) => {
+                    Box::new(db::update_visibility(user, game_id, visibility, &db))
+                }
+                UpdateMember(member) => Box::new(db::update_member(
+                    user,
+                    game_id,
+                    member,
+                    game_config.invite_friends_only,
+                    &db,
+                )),
+                AcceptInvitation => Box::new(db::accept_invitation(user, game_id, &db)),
+                DeclineInvitation => Box::new(db::decline_invitation(user, game_id, &db)),
+                SetReady(ready) => Box::new(db::set_ready(user, game_id, ready, &db)),
+                LeaveGame => Box::new(db::leave_game(user, game_id, &db)),
+            }
+        };
+
+        // After the change is stored, all members of the game are notified.
+        // The game is loaded again, so newly invited members are included.
+        let db = db.clone();
+        Box::new(
+            members_before
+                .and_then(move |members_before| update().map(move |()| members_before))
+                .and_then(move |members_before| {
+                    db::game_unchecked(game_id, &db).map(move |game| (members_before, game))
+                })
+                .map_err(actix_web::Error::from)
+                .map(move |(members_before, game)| {
+                    if let Some(game) = game {
+                        if let Some(message) = push_message(&game, setup_message) {
+                            let mut recipients = game.members.clone();
+                            for member in members_before {
+                                if !recipients.iter().any(|m| m.id == member.id) {
+                                    recipients.push(member);
+                                }
+                            }
+                            hub.notify(&recipients, &message);
+                        }
+                    }
+                    HttpResponse::Ok().json(())
+                }),
+        )
     } else {
-        return Box::new(futures::future::ok(
+        Box::new(futures::future::ok(
             HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
-        ));
+        ))
+    }
+}
+
+/// Translates an applied setup message into the change that is pushed to the
+/// members of the game. This uses the stored game, as the server may modify
+/// the data from the client.
+fn push_message(game: &dto::GameHeader, message: dto::SetupMessage) -> Option<dto::PushMessage> {
+    use dto::SetupMessage::*;
+    match message {
+        SetDescription(_) => Some(dto::PushMessage::DescriptionChanged {
+            game: game.id,
+            description: game.description.clone(),
+        }),
+        UpdateMember(member) => game
+            .members
+            .iter()
+            .find(|m| m.id == member.id)
+            .map(|member| dto::PushMessage::MemberChanged {
+                game: game.id,
+                member: member.clone(),
+            }),
+        SetVisibility(_) | AcceptInvitation | DeclineInvitation | SetReady(_) | LeaveGame => {
+            Some(dto::PushMessage::GameChanged(game.clone()))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StateQuery {
+    /// Only replay this many actions, this gives access to past positions.
+    at: Option<usize>,
+}
+
+fn game_state(
+    path: web::Path<(i64,)>,
+    query: web::Query<StateQuery>,
+    id: Identity,
+    db: web::Data<Pool>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    db::game_state(id.identity(), path.0, query.at, &db)
+        .map_err(actix_web::Error::from)
+        .map(move |state| HttpResponse::Ok().json(state))
+}
+
+/// Returns all actions that were executed in the game, in order.
+fn game_history(
+    path: web::Path<(i64,)>,
+    id: Identity,
+    db: web::Data<Pool>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    db::game_history(id.identity(), path.0, &db)
+        .map_err(actix_web::Error::from)
+        .map(move |history| HttpResponse::Ok().json(history))
+}
+
+/// Returns the legal actions of the current player in the current position.
+/// Nobody can act before the game starts or after it finished.
+fn legal_actions(
+    path: web::Path<(i64,)>,
+    id: Identity,
+    db: web::Data<Pool>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    db::running_game_state(id.identity(), path.0, &db)
+        .map_err(actix_web::Error::from)
+        .map(move |state| {
+            let legal_actions = match state {
+                Some(state) => state.legal_actions(),
+                None => dto::LegalActions::none(),
+            };
+            HttpResponse::Ok().json(legal_actions)
+        })
+}
+
+/// Solves a position of a finished game, see `solver`. The `at` parameter
+/// selects a past position like for the state.
+fn game_analysis(
+    path: web::Path<(i64,)>,
+    query: web::Query<StateQuery>,
+    id: Identity,
+    db: web::Data<Pool>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    db::finished_game_state(id.identity(), path.0, query.at, &db)
+        .map_err(actix_web::Error::from)
+        .and_then(|state| {
+            // Misère positions may take a while to search.
+            web::block(move || solver::analyze(&state)).map_err(|error| match error {
+                BlockingError::Error(error) => error.into(),
+                BlockingError::Canceled => {
+                    actix_web::error::ErrorInternalServerError("The analysis was canceled.")
+                }
+            })
+        })
+        .map(|analysis| HttpResponse::Ok().json(analysis))
+}
+
+/// Executes an action in a running game. The new state is returned and
+/// pushed to all members of the game.
+fn game_action(
+    path: web::Path<(i64,)>,
+    action: web::Json<variant::GameAction>,
+    id: Identity,
+    db: web::Data<Pool>,
+    hub: web::Data<SocketHub>,
+) -> Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>> {
+    let game_id = path.0;
+
+    if let Some(user) = id.identity() {
+        let db_ = db.clone();
+        Box::new(
+            db::execute_action(user, game_id, action.into_inner(), &db)
+                .and_then(move |state| {
+                    // The members are only required to notify them.
+                    db::game_unchecked(game_id, &db_).map(move |game| (state, game))
+                })
+                .map_err(actix_web::Error::from)
+                .map(move |(state, game)| {
+                    if let Some(game) = game {
+                        hub.notify(
+                            &game.members,
+                            &dto::PushMessage::StateChanged {
+                                game: game_id,
+                                state: state.clone(),
+                            },
+                        );
+                        // The last action also changes the status of the game.
+                        if game.status == dto::GameStatus::Finished {
+                            hub.notify(&game.members, &dto::PushMessage::GameChanged(game.clone()));
+                        }
+                    }
+                    HttpResponse::Ok().json(state)
+                }),
+        )
+    } else {
+        Box::new(futures::future::ok(
+            HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()),
+        ))
+    }
+}
+
+/// Opens the websocket connection that pushes changes in the games of the
+/// logged in user.
+fn push_socket(
+    req: HttpRequest,
+    payload: web::Payload,
+    id: Identity,
+    hub: web::Data<SocketHub>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Some(user) = id.identity() {
+        socket::connect(user, &req, payload, &hub)
+    } else {
+        Ok(HttpResponse::Unauthorized().json(SimpleErrorResult::not_logged_in()))
     }
 }
 
@@ -353,7 +999,7 @@ fn dummy_example(// id: Identity,
             id: 99,
             username: "Rolf".to_owned(),
             role: dto::MemberRole::Watcher,
-            accepted: true,
+            ready_state: dto::ReadyState::Accepted,
         }),
     ];
 
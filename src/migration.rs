@@ -0,0 +1,192 @@
+//! Schema migrations
+//!
+//! The database schema is created and updated by the SQL scripts in
+//! `setup/migrations`. They are embedded into the binary and applied when the
+//! server starts. The `schema_version` table records which of them ran.
+
+use rusqlite::{params, Connection};
+
+/// All migrations in the order they are applied. The version of a migration is
+/// its position in this list, starting at 1. Released migrations must never
+/// change, fixes go into a new migration.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../setup/migrations/0001_initial.sql"),
+    include_str!("../setup/migrations/0002_ready_state.sql"),
+    include_str!("../setup/migrations/0003_game_visibility.sql"),
+    include_str!("../setup/migrations/0004_friendship.sql"),
+    include_str!("../setup/migrations/0005_fix_schema.sql"),
+    include_str!("../setup/migrations/0006_game_rules.sql"),
+    include_str!("../setup/migrations/0007_game_type.sql"),
+];
+
+/// Applies all migrations that did not run on this database yet and returns
+/// how many were applied.
+pub fn migrate(conn: &mut Connection) -> Result<usize, failure::Error> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS `schema_version` (
+            `version`	INTEGER NOT NULL PRIMARY KEY,
+            `applied_at`	TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );",
+    )?;
+
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(failure::format_err!(
+            "The database has schema version {}, but this server only knows {} versions.",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+
+    // Migrations rebuild tables, which is only possible while foreign keys are
+    // disabled. This can't be changed inside a transaction.
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let result = apply_migrations(conn, version);
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    result
+}
+
+fn apply_migrations(conn: &mut Connection, version: usize) -> Result<usize, failure::Error> {
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = conn.transaction()?;
+        transaction.execute_batch(migration)?;
+
+        // The foreign keys are not checked while the migration runs.
+        let mut stmt = transaction.prepare("PRAGMA foreign_key_check")?;
+        if stmt.exists(params![])? {
+            return Err(failure::format_err!(
+                "Migration {} violates a foreign key constraint.",
+                index + 1
+            ));
+        }
+        drop(stmt);
+
+        transaction.execute(
+            "insert into schema_version (version) values (?1)",
+            params![(index + 1) as i64],
+        )?;
+        transaction.commit()?;
+    }
+
+    Ok(MIGRATIONS.len() - version)
+}
+
+/// The version of the latest migration that was applied, 0 for a new database.
+pub fn schema_version(conn: &Connection) -> Result<usize, failure::Error> {
+    let version: i64 = conn.query_row(
+        "select coalesce(max(version), 0) from schema_version",
+        params![],
+        |row| row.get(0),
+    )?;
+    Ok(version as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dto;
+    #[test]
+    fn migrate_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(migrate(&mut conn).unwrap(), 0);
+
+        conn.execute_batch(include_str!("../setup/example_data.sql"))
+            .unwrap();
+        let result = conn.execute(
+            "insert into game_member (user, game, role) values (1, 99, 1)",
+            params![],
+        );
+        assert!(result.is_err());
+
+        // Games from before the rules were stored are Nim games with the
+        // classic rules.
+        let (game_type, rules): (_, String) = conn
+            .query_row(
+                "select game_type, rules from game where id = 1",
+                params![],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            crate::variant::GameRules::from_json(game_type, &rules).unwrap(),
+            crate::variant::GameRules::default()
+        );
+    }
+
+    /// The tables created by `setup/install.sql`, before there were
+    /// migrations.
+    const INSTALL_SCRIPT_SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS `user` (
+            `id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+            `username`	INTEGER NOT NULL UNIQUE,
+            `password`	TEXT
+        );
+        CREATE TABLE IF NOT EXISTS `game` (
+            `id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+            `description`	TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS `game_data` (
+            `id`	INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+            `game`	INTEGER NOT NULL,
+            `data`	TEXT NOT NULL,
+            FOREIGN KEY(`game`) REFERENCES `game`(`id`)
+        );
+        CREATE TABLE IF NOT EXISTS `game_member` (
+            `user`  INTEGER NOT NULL,
+            `game`  INTEGER NOT NULL,
+            `role`  INTEGER NOT NULL,
+            `accepted`	INTEGER NOT NULL DEFAULT 0,
+            UNIQUE(`user`,`game`)
+        );";
+
+    #[test]
+    fn fix_databases_from_install_script() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(INSTALL_SCRIPT_SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO `user` (id, username) VALUES (1, '1234');
+             INSERT INTO `game` (id, description) VALUES (1, 'game');
+             INSERT INTO `game_member` (user, game, role, accepted) VALUES (1, 1, 1, 1), (2, 1, 2, 0);",
+        )
+        .unwrap();
+
+        // The integer column converted the name to a number.
+        migrate(&mut conn).unwrap();
+
+        let username_type: String = conn
+            .query_row("select typeof(username) from user", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(username_type, "text");
+        let member_count: i64 = conn
+            .query_row("select count(*) from game_member", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(member_count, 1);
+        // The accepted flag became a ready state.
+        let ready_state: dto::ReadyState = conn
+            .query_row("select ready_state from game_member", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(ready_state, dto::ReadyState::Accepted);
+
+        // The columns and tables that were added later exist.
+        let (status, visibility): (dto::GameStatus, dto::Visibility) = conn
+            .query_row("select status, visibility from game", params![], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(status, dto::GameStatus::Setup);
+        assert_eq!(visibility, dto::Visibility::MembersOnly);
+        conn.execute_batch(
+            "INSERT INTO `friendship` (user, friend) VALUES (1, 1);
+             INSERT INTO `user_block` (user, blocked_user) VALUES (1, 1);",
+        )
+        .unwrap();
+    }
+}
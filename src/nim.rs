@@ -1,92 +1,364 @@
 //! Implementation of the game rules
+//!
+//! The players take turns removing tokens from a single row, see `heap_nim`
+//! for the classical game with several heaps. How many tokens
+//! they may take and who wins is configured by `NimRules`, which is chosen
+//! when the game is created. The game is over as soon as the current player
+//! has no legal move left.
 
+use super::game::{Game, PlayConvention, TurnOrder};
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use std::iter::FromIterator;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-struct Nim {
+/// The most tokens a game can start with.
+pub const MAX_TOKEN_COUNT: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Nim {
+    rules: NimRules,
     tokens: Vec<TokenState>,
-    current_player: u8,
-    player_count: u8,
-    last_token_taken_by: Option<u8>,
+    #[serde(flatten)]
+    turns: TurnOrder,
+}
+
+/// The rule set of a game. Missing fields take their value from the default,
+/// which are the classic rules: 15 tokens, take 1 to 3 of them and whoever
+/// takes the last token wins.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NimRules {
+    pub token_count: usize,
+    /// The fewest tokens a player must take in a move.
+    pub min_take: usize,
+    /// The most tokens a player may take in a move.
+    pub max_take: usize,
+    pub convention: PlayConvention,
+    /// The tokens taken in a move must be next to each other, without gaps
+    /// left by earlier moves.
+    pub contiguous: bool,
+    pub player_count: u8,
+}
+
+impl Default for NimRules {
+    fn default() -> Self {
+        NimRules {
+            token_count: 15,
+            min_take: 1,
+            max_take: 3,
+            convention: PlayConvention::Normal,
+            contiguous: false,
+            player_count: 2,
+        }
+    }
+}
+
+/// The reason why a rule set was rejected, each names the setting that is
+/// out of range.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RulesError {
+    TokenCount,
+    TakeRange,
+    HeapCount,
+    PlayerCount,
+}
+
+impl NimRules {
+    /// Checks that a game with these rules can be played.
+    pub fn validate(&self) -> Result<(), RulesError> {
+        if self.token_count == 0 || self.token_count > MAX_TOKEN_COUNT {
+            Err(RulesError::TokenCount)
+        } else if self.min_take == 0
+            || self.min_take > self.max_take
+            || self.min_take > self.token_count
+        {
+            // The first player must be able to move, or the game would be
+            // over before it started.
+            Err(RulesError::TakeRange)
+        } else if self.player_count < 2 {
+            Err(RulesError::PlayerCount)
+        } else {
+            Ok(())
+        }
+    }
 }
 
-#[derive(Clone)]
-struct NimAction {
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NimAction {
     token_indices: HashSet<usize>,
 }
 
 impl NimAction {
-    fn from_vec(token_indices: Vec<usize>) -> Self {
+    #[cfg(test)]
+    pub fn from_vec(token_indices: Vec<usize>) -> Self {
         NimAction {
-            token_indices: HashSet::from_iter(token_indices),
+            token_indices: token_indices.into_iter().collect(),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum NimError {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NimError {
     TokenAlreadyMissing,
     TokenOutOfBounds,
     ToManyTokens,
     NotEnoughtTokens,
+    /// The rules only allow taking tokens that are next to each other.
+    TokensNotContiguous,
     GameAlreadyOver,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum TokenState {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenState {
     TokenPresent,
     TokenMissing,
 }
 
 impl Nim {
-    fn new(token_count: usize, player_count: u8) -> Self {
+    /// The rules must be valid, see `NimRules::validate`.
+    pub fn new(rules: NimRules) -> Self {
         Nim {
-            tokens: vec![TokenState::TokenPresent; token_count],
-            current_player: 0,
-            player_count,
-            last_token_taken_by: None,
+            tokens: vec![TokenState::TokenPresent; rules.token_count],
+            rules,
+            turns: TurnOrder::default(),
+        }
+    }
+
+    pub fn rules(&self) -> &NimRules {
+        &self.rules
+    }
+
+    /// The runs of tokens that are still present as the index of their first
+    /// token and their length, in order.
+    pub fn runs(&self) -> Vec<(usize, usize)> {
+        let mut runs = Vec::new();
+        let mut first = None;
+        let tokens = self
+            .tokens
+            .iter()
+            .chain(std::iter::once(&TokenState::TokenMissing));
+        for (index, token) in tokens.enumerate() {
+            match (token, first) {
+                (TokenState::TokenPresent, None) => first = Some(index),
+                (TokenState::TokenMissing, Some(start)) => {
+                    runs.push((start, index - start));
+                    first = None;
+                }
+                _ => {}
+            }
+        }
+        runs
+    }
+
+    pub fn present_count(&self) -> usize {
+        self.runs().iter().map(|(_, len)| len).sum()
+    }
+
+    fn has_legal_action(&self) -> bool {
+        if self.rules.contiguous {
+            self.runs()
+                .iter()
+                .any(|(_, len)| *len >= self.rules.min_take)
+        } else {
+            self.present_count() >= self.rules.min_take
+        }
+    }
+
+    /// Every legal action of the current player, ordered by the number of
+    /// taken tokens. There can be very many of them, see
+    /// `legal_action_count`.
+    pub fn legal_actions(&self) -> Box<dyn Iterator<Item = NimAction>> {
+        let state = self.clone();
+        let sizes = self.rules.min_take..=self.rules.max_take;
+        Box::new(sizes.flat_map(move |size| state.legal_actions_taking(size)))
+    }
+
+    /// The legal actions of the current player that take exactly `size`
+    /// tokens, in a fixed order.
+    pub fn legal_actions_taking(&self, size: usize) -> Box<dyn Iterator<Item = NimAction>> {
+        if !self.allows_taking(size) {
+            return Box::new(std::iter::empty());
+        }
+
+        if self.rules.contiguous {
+            Box::new(
+                self.runs()
+                    .into_iter()
+                    .filter(move |(_, len)| *len >= size)
+                    .flat_map(move |(first, len)| {
+                        (first..=first + len - size).map(move |start| NimAction {
+                            token_indices: (start..start + size).collect(),
+                        })
+                    }),
+            )
+        } else {
+            let present: Vec<usize> = (0..self.tokens.len())
+                .filter(|i| self.tokens[*i] == TokenState::TokenPresent)
+                .collect();
+            Box::new(
+                Combinations::new(present, size).map(|token_indices| NimAction {
+                    token_indices: token_indices.into_iter().collect(),
+                }),
+            )
+        }
+    }
+
+    /// The number of legal actions of the current player, without listing
+    /// them.
+    pub fn legal_action_count(&self) -> u128 {
+        (self.rules.min_take..=self.rules.max_take)
+            .map(|size| self.legal_action_count_taking(size))
+            .sum()
+    }
+
+    /// The number of legal actions of the current player that take exactly
+    /// `size` tokens.
+    pub fn legal_action_count_taking(&self, size: usize) -> u128 {
+        if !self.allows_taking(size) {
+            return 0;
+        }
+
+        if self.rules.contiguous {
+            // A run of `len` tokens has `len - size + 1` windows of a size.
+            self.runs()
+                .iter()
+                .filter(|(_, len)| *len >= size)
+                .map(|(_, len)| (len - size + 1) as u128)
+                .sum()
+        } else {
+            let present = self.present_count();
+            if size <= present {
+                binomial(present, size)
+            } else {
+                0
+            }
         }
     }
 
+    /// Whether the game is running and the rules allow a move of this size.
+    fn allows_taking(&self, size: usize) -> bool {
+        self.turns.winner().is_none() && self.rules.min_take <= size && size <= self.rules.max_take
+    }
+
+    /// The tokens that are part of at least one legal action, in order.
+    pub fn selectable_tokens(&self) -> Vec<usize> {
+        if self.turns.winner().is_some() {
+            return Vec::new();
+        }
+
+        // While the game is running, every token is selectable unless takes
+        // must be contiguous and its run is too short.
+        self.runs()
+            .into_iter()
+            .filter(|(_, len)| !self.rules.contiguous || *len >= self.rules.min_take)
+            .flat_map(|(first, len)| first..first + len)
+            .collect()
+    }
+}
+
+impl Game for Nim {
+    type Action = NimAction;
+    type Error = NimError;
+
     fn execute(&mut self, action: &NimAction) -> Result<(), NimError> {
         use NimError::*;
         use TokenState::*;
         // Assert, that the move is legal.
-        if self.last_token_taken_by.is_some() {
+        let indices = &action.token_indices;
+        if self.turns.winner().is_some() {
             Err(GameAlreadyOver)
-        } else if action.token_indices.is_empty() {
+        } else if indices.len() < self.rules.min_take {
             Err(NotEnoughtTokens)
-        } else if action.token_indices.len() > 3 {
+        } else if indices.len() > self.rules.max_take {
             Err(ToManyTokens)
-        } else if action.token_indices.iter().any(|i| *i >= self.tokens.len()) {
+        } else if indices.iter().any(|i| *i >= self.tokens.len()) {
             Err(TokenOutOfBounds)
-        } else if action
-            .token_indices
-            .iter()
-            .any(|i| self.tokens[*i] == TokenMissing)
-        {
+        } else if indices.iter().any(|i| self.tokens[*i] == TokenMissing) {
             Err(TokenAlreadyMissing)
+        } else if self.rules.contiguous && !is_contiguous(indices) {
+            // All tokens between the first and the last one are present, as
+            // all of them are taken.
+            Err(TokensNotContiguous)
         } else {
             // Mark tokens as removed.
-            action
-                .token_indices
-                .iter()
-                .for_each(|i| self.tokens[*i] = TokenMissing);
-            // Check if the game is over.
-            if self.tokens.iter().all(|t| *t == TokenMissing) {
-                self.last_token_taken_by = Some(self.current_player);
-            } else {
-                // Proceed to the next player.
-                self.current_player += 1;
-                if self.current_player == self.player_count {
-                    self.current_player = 0;
-                }
-            }
+            indices.iter().for_each(|i| self.tokens[*i] = TokenMissing);
+
+            // Proceed to the next player, who may be unable to move.
+            let can_move = self.has_legal_action();
+            self.turns
+                .next_turn(self.rules.player_count, self.rules.convention, can_move);
 
             Ok(())
         }
     }
+
+    fn current_player(&self) -> u8 {
+        self.turns.current_player()
+    }
+
+    fn winner(&self) -> Option<u8> {
+        self.turns.winner()
+    }
+
+    fn player_count(&self) -> u8 {
+        self.rules.player_count
+    }
+}
+
+/// The number of ways to choose `k` of `n` elements. This does not overflow
+/// for up to `MAX_TOKEN_COUNT` elements.
+fn binomial(n: usize, k: usize) -> u128 {
+    let k = k.min(n - k);
+    // Each intermediate result is a binomial coefficient itself.
+    (1..=k).fold(1, |result, i| result * (n - k + i) as u128 / i as u128)
+}
+
+/// All subsets with `size` of the given items, in lexicographic order.
+struct Combinations {
+    items: Vec<usize>,
+    /// The positions in `items` of the next subset, `None` when done.
+    positions: Option<Vec<usize>>,
+}
+
+impl Combinations {
+    fn new(items: Vec<usize>, size: usize) -> Self {
+        let positions = if size <= items.len() {
+            Some((0..size).collect())
+        } else {
+            None
+        };
+        Combinations { items, positions }
+    }
+}
+
+impl Iterator for Combinations {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Vec<usize>> {
+        let items = &self.items;
+        let positions = self.positions.as_mut()?;
+        let subset = positions.iter().map(|p| items[*p]).collect();
+
+        // Advance the last position that has room to move right and reset
+        // all positions after it.
+        let (n, size) = (items.len(), positions.len());
+        match (0..size).rev().find(|i| positions[*i] < n - size + i) {
+            Some(i) => {
+                positions[i] += 1;
+                for j in i + 1..size {
+                    positions[j] = positions[j - 1] + 1;
+                }
+            }
+            None => self.positions = None,
+        }
+        Some(subset)
+    }
+}
+
+fn is_contiguous(indices: &HashSet<usize>) -> bool {
+    match (indices.iter().min(), indices.iter().max()) {
+        (Some(first), Some(last)) => last - first + 1 == indices.len(),
+        _ => true,
+    }
 }
 
 #[cfg(test)]
@@ -94,7 +366,7 @@ mod test {
     use super::*;
     #[test]
     fn take_some_tokens() {
-        let mut game = Nim::new(15, 2);
+        let mut game = Nim::new(NimRules::default());
         game.execute(&NimAction::from_vec(vec![2, 4, 6])).unwrap();
         game.execute(&NimAction::from_vec(vec![1, 7])).unwrap();
         game.execute(&NimAction::from_vec(vec![11])).unwrap();
@@ -102,16 +374,183 @@ mod test {
         game.execute(&NimAction::from_vec(vec![8, 9])).unwrap();
         game.execute(&NimAction::from_vec(vec![12, 14])).unwrap();
         game.execute(&NimAction::from_vec(vec![0, 10])).unwrap();
-        assert_eq!(game.last_token_taken_by, Some(0));
+        assert_eq!(game.winner(), Some(0));
+        assert_eq!(game.current_player(), 0);
+        assert!(game.is_over());
     }
 
     #[test]
     fn test_double_take() {
-        let mut game = Nim::new(15, 2);
+        let mut game = Nim::new(NimRules::default());
         game.execute(&NimAction::from_vec(vec![2, 4, 6])).unwrap();
         let game_clone = game.clone();
         let result = game.execute(&NimAction::from_vec(vec![1, 7, 2]));
         assert_eq!(result, Err(NimError::TokenAlreadyMissing));
         assert_eq!(game_clone, game);
     }
+
+    #[test]
+    fn replay_actions() {
+        let actions = vec![
+            NimAction::from_vec(vec![2, 4, 6]),
+            NimAction::from_vec(vec![1, 7]),
+        ];
+        let mut game = Nim::new(NimRules::default());
+        for action in &actions {
+            game.execute(action).unwrap();
+        }
+        assert_eq!(
+            crate::game::replay(Nim::new(NimRules::default()), &actions).unwrap(),
+            game
+        );
+
+        let illegal = vec![NimAction::from_vec(vec![2]), NimAction::from_vec(vec![2])];
+        assert!(crate::game::replay(Nim::new(NimRules::default()), &illegal).is_err());
+    }
+
+    #[test]
+    fn turn_rotation() {
+        let mut game = Nim::new(NimRules {
+            player_count: 3,
+            ..NimRules::default()
+        });
+        assert_eq!(game.current_player(), 0);
+        game.execute(&NimAction::from_vec(vec![0])).unwrap();
+        assert_eq!(game.current_player(), 1);
+        game.execute(&NimAction::from_vec(vec![1])).unwrap();
+        assert_eq!(game.current_player(), 2);
+        game.execute(&NimAction::from_vec(vec![2])).unwrap();
+        assert_eq!(game.current_player(), 0);
+        assert_eq!(game.winner(), None);
+    }
+
+    #[test]
+    fn configured_take_range() {
+        let mut game = Nim::new(NimRules {
+            token_count: 9,
+            min_take: 2,
+            max_take: 4,
+            ..NimRules::default()
+        });
+        let result = game.execute(&NimAction::from_vec(vec![0]));
+        assert_eq!(result, Err(NimError::NotEnoughtTokens));
+        let result = game.execute(&NimAction::from_vec(vec![0, 1, 2, 3, 4]));
+        assert_eq!(result, Err(NimError::ToManyTokens));
+        game.execute(&NimAction::from_vec(vec![0, 1, 2, 3]))
+            .unwrap();
+        game.execute(&NimAction::from_vec(vec![4, 5, 6, 7]))
+            .unwrap();
+        // A single token is left, which the first player can't take.
+        assert_eq!(game.winner(), Some(1));
+        let result = game.execute(&NimAction::from_vec(vec![8]));
+        assert_eq!(result, Err(NimError::GameAlreadyOver));
+    }
+
+    #[test]
+    fn misere_play() {
+        let mut game = Nim::new(NimRules {
+            token_count: 4,
+            convention: PlayConvention::Misere,
+            ..NimRules::default()
+        });
+        game.execute(&NimAction::from_vec(vec![0, 1, 2])).unwrap();
+        assert_eq!(game.winner(), None);
+        game.execute(&NimAction::from_vec(vec![3])).unwrap();
+        // The second player took the last token and loses.
+        assert_eq!(game.winner(), Some(0));
+    }
+
+    #[test]
+    fn contiguous_takes() {
+        let mut game = Nim::new(NimRules {
+            token_count: 5,
+            min_take: 2,
+            contiguous: true,
+            ..NimRules::default()
+        });
+        let result = game.execute(&NimAction::from_vec(vec![0, 2]));
+        assert_eq!(result, Err(NimError::TokensNotContiguous));
+        game.execute(&NimAction::from_vec(vec![2, 3])).unwrap();
+        assert_eq!(game.winner(), None);
+        // The remaining tokens 0, 1 and 4 are no longer next to each other.
+        let result = game.execute(&NimAction::from_vec(vec![1, 4]));
+        assert_eq!(result, Err(NimError::TokensNotContiguous));
+        game.execute(&NimAction::from_vec(vec![0, 1])).unwrap();
+        // Token 4 is alone, so the second player made the last move.
+        assert_eq!(game.winner(), Some(1));
+    }
+
+    #[test]
+    fn enumerate_legal_actions() {
+        let rules = NimRules {
+            token_count: 5,
+            ..NimRules::default()
+        };
+        let game = Nim::new(rules.clone());
+        // Choose 1, 2 or 3 of 5 tokens.
+        assert_eq!(game.legal_action_count(), 5 + 10 + 10);
+        assert_eq!(game.legal_actions().count(), 25);
+        for action in game.legal_actions() {
+            game.clone().execute(&action).unwrap();
+        }
+
+        let mut game = Nim::new(NimRules {
+            min_take: 2,
+            contiguous: true,
+            ..rules
+        });
+        assert_eq!(game.legal_action_count(), 4 + 3);
+        game.execute(&NimAction::from_vec(vec![2])).unwrap_err();
+        game.execute(&NimAction::from_vec(vec![1, 2])).unwrap();
+        // Only the tokens 3 and 4 can be taken together.
+        assert_eq!(game.selectable_tokens(), vec![3, 4]);
+        let actions: Vec<NimAction> = game.legal_actions().collect();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].token_indices, vec![3, 4].into_iter().collect());
+        assert_eq!(game.legal_action_count(), 1);
+
+        game.execute(&actions[0]).unwrap();
+        assert!(game.is_over());
+        assert_eq!(game.legal_action_count(), 0);
+        assert!(game.selectable_tokens().is_empty());
+    }
+
+    #[test]
+    fn count_actions_on_large_boards() {
+        let game = Nim::new(NimRules {
+            token_count: MAX_TOKEN_COUNT,
+            max_take: MAX_TOKEN_COUNT,
+            ..NimRules::default()
+        });
+        // Every subset except the empty one.
+        assert_eq!(game.legal_action_count(), (1 << 100) - 1);
+        assert_eq!(binomial(100, 50), 100_891_344_545_564_193_334_812_497_256);
+    }
+
+    #[test]
+    fn validate_rules() {
+        assert_eq!(NimRules::default().validate(), Ok(()));
+        let too_many_tokens = NimRules {
+            token_count: MAX_TOKEN_COUNT + 1,
+            ..NimRules::default()
+        };
+        assert_eq!(too_many_tokens.validate(), Err(RulesError::TokenCount));
+        let empty_range = NimRules {
+            min_take: 4,
+            ..NimRules::default()
+        };
+        assert_eq!(empty_range.validate(), Err(RulesError::TakeRange));
+        let no_first_move = NimRules {
+            token_count: 2,
+            min_take: 3,
+            max_take: 3,
+            ..NimRules::default()
+        };
+        assert_eq!(no_first_move.validate(), Err(RulesError::TakeRange));
+        let single_player = NimRules {
+            player_count: 1,
+            ..NimRules::default()
+        };
+        assert_eq!(single_player.validate(), Err(RulesError::PlayerCount));
+    }
 }
@@ -0,0 +1,155 @@
+//! Websocket connection on /api/socket that pushes changes to clients.
+//!
+//! Clients still send all their requests through the http api. The socket is
+//! only used by the server to notify every connected member of a game when
+//! something about the game changes, so the client does not need to poll.
+
+use super::{csrf, dto};
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::{Bytes, BytesMut};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Async, Poll, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Keeps track of all open websocket connections, grouped by username.
+/// A user may have several connections, e.g. when the client is open in
+/// multiple browser tabs.
+#[derive(Default)]
+pub struct SocketHub {
+    connections: Mutex<HashMap<String, Vec<UnboundedSender<ws::Message>>>>,
+}
+
+impl SocketHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register(&self, username: String) -> UnboundedReceiver<ws::Message> {
+        let (sender, receiver) = unbounded();
+        let mut connections = self.connections.lock().unwrap();
+        connections.entry(username).or_default().push(sender);
+        receiver
+    }
+
+    /// Sends the message to every connection of the given game members.
+    /// Connections that were closed in the meantime are cleaned up here.
+    pub fn notify(&self, members: &[dto::Member], message: &dto::PushMessage) {
+        let text = match serde_json::to_string(message) {
+            Ok(text) => text,
+            Err(_) => return,
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+        for member in members {
+            if let Some(senders) = connections.get_mut(&member.username) {
+                senders.retain(|sender| {
+                    sender
+                        .unbounded_send(ws::Message::Text(text.clone()))
+                        .is_ok()
+                });
+                if senders.is_empty() {
+                    connections.remove(&member.username);
+                }
+            }
+        }
+    }
+}
+
+/// Performs the websocket handshake and registers the connection for the user.
+/// Pages of other websites can't open a socket with the cookie of the user.
+pub fn connect(
+    username: String,
+    req: &HttpRequest,
+    payload: web::Payload,
+    hub: &SocketHub,
+) -> Result<HttpResponse, actix_web::Error> {
+    csrf::verify_origin(req)?;
+    let mut response = ws::handshake(req.head())?;
+    let receiver = hub.register(username);
+
+    Ok(response.streaming(SocketStream {
+        payload,
+        payload_done: false,
+        buffer: BytesMut::new(),
+        codec: ws::Codec::new(),
+        receiver,
+        replies: VecDeque::new(),
+        closed: false,
+    }))
+}
+
+/// The body of the websocket response. It reads control frames sent by the
+/// client and writes both the replies and the pushed messages as frames.
+struct SocketStream {
+    payload: web::Payload,
+    payload_done: bool,
+    buffer: BytesMut,
+    codec: ws::Codec,
+    receiver: UnboundedReceiver<ws::Message>,
+    replies: VecDeque<ws::Message>,
+    closed: bool,
+}
+
+impl SocketStream {
+    fn read_incoming(&mut self) -> Result<(), actix_web::Error> {
+        while !self.payload_done {
+            match self.payload.poll()? {
+                Async::Ready(Some(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Async::Ready(None) => self.payload_done = true,
+                Async::NotReady => break,
+            }
+        }
+
+        while let Some(frame) = self.codec.decode(&mut self.buffer)? {
+            match frame {
+                ws::Frame::Ping(message) => self.replies.push_back(ws::Message::Pong(message)),
+                ws::Frame::Close(reason) => self.replies.push_back(ws::Message::Close(reason)),
+                // Clients talk to the server through the http api, so we
+                // ignore all other frames.
+                _ => {}
+            }
+        }
+
+        if self.payload_done && self.replies.is_empty() {
+            // The client went away without a close frame.
+            self.closed = true;
+        }
+
+        Ok(())
+    }
+}
+
+impl Stream for SocketStream {
+    type Item = Bytes;
+    type Error = actix_web::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, actix_web::Error> {
+        if !self.closed {
+            self.read_incoming()?;
+        }
+        if self.closed {
+            return Ok(Async::Ready(None));
+        }
+
+        let message = if let Some(reply) = self.replies.pop_front() {
+            reply
+        } else {
+            match self.receiver.poll() {
+                Ok(Async::Ready(Some(message))) => message,
+                Ok(Async::Ready(None)) | Err(()) => ws::Message::Close(None),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        };
+
+        if let ws::Message::Close(_) = message {
+            self.closed = true;
+        }
+
+        let mut frame = BytesMut::new();
+        self.codec.encode(message, &mut frame)?;
+        Ok(Async::Ready(Some(frame.freeze())))
+    }
+}
@@ -0,0 +1,459 @@
+//! Perfect play analysis
+//!
+//! Every game type is a sum of independent components: the tokens of a row,
+//! the runs of a row when takes must be contiguous, or the heaps. A move
+//! changes a single component, taking from a run may split it in two. With
+//! the normal convention, the Sprague–Grundy theorem solves such sums: the
+//! player to move loses exactly when the XOR of the Grundy values of the
+//! components is zero. Misère play has no such shortcut. Heaps follow
+//! Bouton's misère rule, the other games are searched position by position.
+
+use super::dto::Analysis;
+use super::game::{Game, PlayConvention};
+use super::variant::{GameAction, GameState, MAX_LISTED_ACTIONS};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The most positions a misère search may visit before it gives up.
+const MAX_SEARCHED_POSITIONS: usize = 200_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SolverError {
+    /// Perfect play is only defined for two players, more players could
+    /// gang up on one of them.
+    TooManyPlayers,
+    /// The search of a misère position visited `MAX_SEARCHED_POSITIONS`
+    /// without an answer.
+    TooComplex,
+}
+
+impl std::fmt::Display for SolverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SolverError::TooManyPlayers => write!(f, "Only games of two players can be solved."),
+            SolverError::TooComplex => write!(f, "The position is too complex to solve."),
+        }
+    }
+}
+
+/// Solves the position for the player to move and finds their winning
+/// actions. This works on finished games, where it reports the winner.
+pub fn analyze(state: &GameState) -> Result<Analysis, SolverError> {
+    if state.player_count() != 2 {
+        return Err(SolverError::TooManyPlayers);
+    }
+
+    let current_player = state.current_player();
+    if let Some(winner) = state.winner() {
+        return Ok(Analysis {
+            winner,
+            current_player_wins: winner == current_player,
+            winning_action_count: 0,
+            winning_actions: Some(Vec::new()),
+        });
+    }
+
+    let mut solver = Solver::new(state);
+    let (winner, (winning_action_count, winning_actions)) =
+        if solver.mover_wins(components(state))? {
+            (current_player, solver.winning_actions(state)?)
+        } else {
+            (1 - current_player, (0, Some(Vec::new())))
+        };
+
+    Ok(Analysis {
+        winner,
+        current_player_wins: winner == current_player,
+        winning_action_count,
+        winning_actions,
+    })
+}
+
+/// The sizes of the independent components of a position.
+fn components(state: &GameState) -> Vec<usize> {
+    match state {
+        GameState::Nim(state) if state.rules().contiguous => {
+            state.runs().into_iter().map(|(_, len)| len).collect()
+        }
+        // Without the contiguous rule, it does not matter which tokens are
+        // left, only how many.
+        GameState::Nim(state) => vec![state.present_count()],
+        GameState::HeapNim(state) => state.heaps().to_vec(),
+    }
+}
+
+/// The moves the rules allow in a component.
+#[derive(Clone, Copy, Debug)]
+enum Take {
+    /// Take any number of tokens, as from the heaps of `heap_nim`.
+    Any,
+    /// Take between `min` and `max` tokens.
+    Range { min: usize, max: usize },
+    /// Take between `min` and `max` adjacent tokens, which may split the run
+    /// in two.
+    Adjacent { min: usize, max: usize },
+}
+
+impl Take {
+    fn of(state: &GameState) -> Self {
+        match state {
+            GameState::Nim(state) => {
+                let rules = state.rules();
+                let (min, max) = (rules.min_take, rules.max_take);
+                if rules.contiguous {
+                    Take::Adjacent { min, max }
+                } else {
+                    Take::Range { min, max }
+                }
+            }
+            GameState::HeapNim(_) => Take::Any,
+        }
+    }
+
+    fn can_move(self, size: usize) -> bool {
+        match self {
+            Take::Any => size > 0,
+            Take::Range { min, .. } | Take::Adjacent { min, .. } => size >= min,
+        }
+    }
+
+    /// The components that are left of a component by each move. A size of
+    /// zero means that nothing is left.
+    fn options(self, size: usize) -> Vec<(usize, usize)> {
+        match self {
+            Take::Any => (1..=size).map(|take| (size - take, 0)).collect(),
+            Take::Range { min, max } => {
+                (min..=max.min(size)).map(|take| (size - take, 0)).collect()
+            }
+            // Splits that only differ in their order are the same.
+            Take::Adjacent { min, max } => (min..=max.min(size))
+                .flat_map(|take| {
+                    let rest = size - take;
+                    (0..=rest / 2).map(move |left| (left, rest - left))
+                })
+                .collect(),
+        }
+    }
+}
+
+struct Solver {
+    moves: Take,
+    convention: PlayConvention,
+    /// The Grundy value of each component size that can occur.
+    grundy: Vec<usize>,
+    /// The misère positions that were searched, as sorted component sizes
+    /// without the components that allow no move.
+    searched: HashMap<Vec<usize>, bool>,
+}
+
+impl Solver {
+    fn new(state: &GameState) -> Self {
+        let moves = Take::of(state);
+        let largest = components(state).into_iter().max().unwrap_or(0);
+        let convention = match state {
+            GameState::Nim(state) => state.rules().convention,
+            GameState::HeapNim(state) => state.rules().convention,
+        };
+        Solver {
+            moves,
+            convention,
+            grundy: grundy_values(moves, largest),
+            searched: HashMap::new(),
+        }
+    }
+
+    fn nim_sum(&self, components: &[usize]) -> usize {
+        components
+            .iter()
+            .fold(0, |sum, size| sum ^ self.grundy[*size])
+    }
+
+    /// Whether the player to move wins the position of these components.
+    fn mover_wins(&mut self, components: Vec<usize>) -> Result<bool, SolverError> {
+        match (self.convention, self.moves) {
+            (PlayConvention::Normal, _) => Ok(self.nim_sum(&components) != 0),
+            (PlayConvention::Misere, Take::Any) => {
+                // Bouton: play as in normal Nim, unless no heap has more than
+                // one token. Then the player to move wins if the number of
+                // heaps is even, which leaves the last token to the other.
+                if components.iter().all(|size| *size <= 1) {
+                    Ok(components.iter().filter(|size| **size == 1).count() % 2 == 0)
+                } else {
+                    Ok(self.nim_sum(&components) != 0)
+                }
+            }
+            (PlayConvention::Misere, _) => self.search_misere(components),
+        }
+    }
+
+    /// Searches a misère position, where the player who can't move wins.
+    fn search_misere(&mut self, mut position: Vec<usize>) -> Result<bool, SolverError> {
+        let moves = self.moves;
+        position.retain(|size| moves.can_move(*size));
+        position.sort_unstable();
+        if let Some(wins) = self.searched.get(&position) {
+            return Ok(*wins);
+        }
+        if self.searched.len() >= MAX_SEARCHED_POSITIONS {
+            return Err(SolverError::TooComplex);
+        }
+
+        let mut next_positions = Vec::new();
+        for (index, size) in position.iter().enumerate() {
+            // Equal components have the same moves.
+            if index > 0 && position[index - 1] == *size {
+                continue;
+            }
+            for (left, right) in moves.options(*size) {
+                let mut next = position.clone();
+                next[index] = left;
+                next.push(right);
+                next_positions.push(next);
+            }
+        }
+        // Misère and normal play mostly agree, so the moves that win under
+        // normal play are tried first.
+        next_positions.sort_by_key(|next| self.nim_sum(next) != 0);
+
+        let mut wins = position.is_empty();
+        for next in next_positions {
+            if !self.search_misere(next)? {
+                wins = true;
+                break;
+            }
+        }
+
+        self.searched.insert(position, wins);
+        Ok(wins)
+    }
+
+    /// The number of actions that leave a lost position to the other player,
+    /// and the actions themselves unless there are more than
+    /// `MAX_LISTED_ACTIONS`.
+    fn winning_actions(
+        &mut self,
+        state: &GameState,
+    ) -> Result<(u128, Option<Vec<GameAction>>), SolverError> {
+        match state {
+            GameState::Nim(nim) if !nim.rules().contiguous => {
+                // All actions that take the same number of tokens lead to the
+                // same position, so they can be counted without listing them.
+                let present = nim.present_count();
+                let mut sizes = Vec::new();
+                for size in nim.rules().min_take..=nim.rules().max_take.min(present) {
+                    if !self.mover_wins(vec![present - size])? {
+                        sizes.push(size);
+                    }
+                }
+                let count: u128 = sizes
+                    .iter()
+                    .map(|size| nim.legal_action_count_taking(*size))
+                    .sum();
+                let actions = if count <= MAX_LISTED_ACTIONS as u128 {
+                    Some(
+                        sizes
+                            .iter()
+                            .flat_map(|size| nim.legal_actions_taking(*size))
+                            .map(GameAction::Nim)
+                            .collect(),
+                    )
+                } else {
+                    None
+                };
+                Ok((count, actions))
+            }
+            _ => {
+                // Runs and heaps allow few enough actions to try all of them.
+                let actions: Box<dyn Iterator<Item = GameAction>> = match state {
+                    GameState::Nim(nim) => Box::new(nim.legal_actions().map(GameAction::Nim)),
+                    GameState::HeapNim(nim) => {
+                        Box::new(nim.legal_actions().map(GameAction::HeapNim))
+                    }
+                };
+                let mut winning = Vec::new();
+                for action in actions {
+                    let mut next = state.clone();
+                    if next.execute(&action).is_ok() && !self.mover_wins(components(&next))? {
+                        winning.push(action);
+                    }
+                }
+                let count = winning.len() as u128;
+                if winning.len() > MAX_LISTED_ACTIONS {
+                    Ok((count, None))
+                } else {
+                    Ok((count, Some(winning)))
+                }
+            }
+        }
+    }
+}
+
+/// The Grundy values of all component sizes up to `largest`. A component's
+/// value is the smallest value that none of its moves leads to.
+fn grundy_values(moves: Take, largest: usize) -> Vec<usize> {
+    let mut grundy: Vec<usize> = Vec::with_capacity(largest + 1);
+    for size in 0..=largest {
+        let reachable: HashSet<usize> = moves
+            .options(size)
+            .into_iter()
+            .map(|(left, right)| grundy[left] ^ grundy[right])
+            .collect();
+        grundy.push((0..).find(|value| !reachable.contains(value)).unwrap());
+    }
+    grundy
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::heap_nim::{HeapNimAction, HeapNimRules};
+    use crate::nim::NimRules;
+    use crate::variant::GameRules;
+
+    /// The winner with perfect play and the number of winning actions,
+    /// found by trying every action. Known states are keyed by their JSON.
+    fn brute_force(state: &GameState, known: &mut HashMap<String, (u8, u128)>) -> (u8, u128) {
+        let key = serde_json::to_string(state).unwrap();
+        if let Some(result) = known.get(&key) {
+            return *result;
+        }
+
+        let player = state.current_player();
+        let result = if let Some(winner) = state.winner() {
+            (winner, 0)
+        } else {
+            let actions: Vec<GameAction> = match state {
+                GameState::Nim(nim) => nim.legal_actions().map(GameAction::Nim).collect(),
+                GameState::HeapNim(nim) => nim.legal_actions().map(GameAction::HeapNim).collect(),
+            };
+            let mut winning = 0;
+            for action in &actions {
+                let mut next = state.clone();
+                next.execute(action).unwrap();
+                if brute_force(&next, known).0 == player {
+                    winning += 1;
+                }
+            }
+            if winning > 0 {
+                (player, winning)
+            } else {
+                (1 - player, 0)
+            }
+        };
+        known.insert(key, result);
+        result
+    }
+
+    #[test]
+    fn classic_rules() {
+        let analysis = analyze(&GameState::new(GameRules::default())).unwrap();
+        // Taking 3 of 15 tokens leaves a multiple of 4.
+        assert_eq!(analysis.winner, 0);
+        assert!(analysis.current_player_wins);
+        assert_eq!(analysis.winning_action_count, 455);
+        assert_eq!(analysis.winning_actions.unwrap().len(), 455);
+
+        let lost = GameState::new(GameRules::Nim(NimRules {
+            token_count: 16,
+            ..NimRules::default()
+        }));
+        let analysis = analyze(&lost).unwrap();
+        assert_eq!(analysis.winner, 1);
+        assert!(!analysis.current_player_wins);
+        assert_eq!(analysis.winning_action_count, 0);
+    }
+
+    #[test]
+    fn kayles_grundy_values() {
+        // Taking one or two adjacent pins is the game of Kayles.
+        let moves = Take::Adjacent { min: 1, max: 2 };
+        assert_eq!(
+            grundy_values(moves, 12),
+            vec![0, 1, 2, 3, 1, 4, 3, 2, 1, 4, 2, 6, 4]
+        );
+    }
+
+    #[test]
+    fn heaps_follow_bouton() {
+        let state = GameState::new(GameRules::HeapNim(HeapNimRules::default()));
+        let analysis = analyze(&state).unwrap();
+        // 3 ^ 4 ^ 5 = 2, only 1 ^ 4 ^ 5 is zero.
+        assert_eq!(analysis.winning_action_count, 1);
+        assert!(matches!(
+            analysis.winning_actions.unwrap().as_slice(),
+            [GameAction::HeapNim(HeapNimAction { heap: 0, count: 2 })]
+        ));
+
+        let misere = |heaps: Vec<usize>| {
+            GameState::new(GameRules::HeapNim(HeapNimRules {
+                heaps,
+                convention: PlayConvention::Misere,
+                ..HeapNimRules::default()
+            }))
+        };
+        assert!(analyze(&misere(vec![1, 1])).unwrap().current_player_wins);
+        assert!(!analyze(&misere(vec![1, 1, 1])).unwrap().current_player_wins);
+        assert!(!analyze(&misere(vec![2, 2])).unwrap().current_player_wins);
+    }
+
+    #[test]
+    fn agree_with_brute_force() {
+        for &convention in &[PlayConvention::Normal, PlayConvention::Misere] {
+            for &contiguous in &[false, true] {
+                for &(min_take, max_take) in &[(1, 2), (1, 3), (2, 3), (2, 5)] {
+                    for token_count in min_take..=8 {
+                        let state = GameState::new(GameRules::Nim(NimRules {
+                            token_count,
+                            min_take,
+                            max_take,
+                            convention,
+                            contiguous,
+                            ..NimRules::default()
+                        }));
+                        let analysis = analyze(&state).unwrap();
+                        assert_eq!(
+                            (analysis.winner, analysis.winning_action_count),
+                            brute_force(&state, &mut HashMap::new()),
+                            "{:?}",
+                            state
+                        );
+                    }
+                }
+            }
+            for heaps in &[vec![1, 2, 3], vec![2, 2, 1], vec![4, 1], vec![1, 1, 1]] {
+                let state = GameState::new(GameRules::HeapNim(HeapNimRules {
+                    heaps: heaps.clone(),
+                    convention,
+                    ..HeapNimRules::default()
+                }));
+                let analysis = analyze(&state).unwrap();
+                assert_eq!(
+                    (analysis.winner, analysis.winning_action_count),
+                    brute_force(&state, &mut HashMap::new())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finished_and_unsupported_games() {
+        let mut state = GameState::new(GameRules::Nim(NimRules {
+            token_count: 2,
+            ..NimRules::default()
+        }));
+        let take_all = serde_json::from_str(r#"{"token_indices":[0,1]}"#).unwrap();
+        state.execute(&take_all).unwrap();
+        let analysis = analyze(&state).unwrap();
+        assert_eq!(analysis.winner, 0);
+        assert_eq!(analysis.winning_action_count, 0);
+
+        let three_players = GameState::new(GameRules::Nim(NimRules {
+            player_count: 3,
+            ..NimRules::default()
+        }));
+        assert_eq!(
+            analyze(&three_players).unwrap_err(),
+            SolverError::TooManyPlayers
+        );
+    }
+}
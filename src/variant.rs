@@ -0,0 +1,253 @@
+//! The game types the server can host
+//!
+//! A game is created with the rules of one game type. The server handles the
+//! states and actions of all games through the enums of this module, which
+//! pass everything on to the game type of the game. States and actions are
+//! sent in the format of their game type, so the clients of single heap
+//! games do not notice the other types.
+
+use super::dto::LegalActions;
+use super::game::Game;
+use super::heap_nim::{HeapNim, HeapNimAction, HeapNimError, HeapNimRules};
+use super::nim::{Nim, NimAction, NimError, NimRules, RulesError};
+use serde::{Deserialize, Serialize};
+
+/// The rules a game is created with, they select the game type.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameRules {
+    /// A single row of tokens, see `nim`.
+    Nim(NimRules),
+    /// Several heaps of tokens, see `heap_nim`.
+    HeapNim(HeapNimRules),
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        GameRules::Nim(NimRules::default())
+    }
+}
+
+impl GameRules {
+    /// Checks that a game with these rules can be played.
+    pub fn validate(&self) -> Result<(), RulesError> {
+        match self {
+            GameRules::Nim(rules) => rules.validate(),
+            GameRules::HeapNim(rules) => rules.validate(),
+        }
+    }
+
+    pub fn player_count(&self) -> u8 {
+        match self {
+            GameRules::Nim(rules) => rules.player_count,
+            GameRules::HeapNim(rules) => rules.player_count,
+        }
+    }
+
+    pub fn game_type(&self) -> GameType {
+        match self {
+            GameRules::Nim(_) => GameType::Nim,
+            GameRules::HeapNim(_) => GameType::HeapNim,
+        }
+    }
+
+    /// The rules without the game type, which is stored in its own column.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        match self {
+            GameRules::Nim(rules) => serde_json::to_string(rules),
+            GameRules::HeapNim(rules) => serde_json::to_string(rules),
+        }
+    }
+
+    pub fn from_json(game_type: GameType, json: &str) -> serde_json::Result<Self> {
+        Ok(match game_type {
+            GameType::Nim => GameRules::Nim(serde_json::from_str(json)?),
+            GameType::HeapNim => GameRules::HeapNim(serde_json::from_str(json)?),
+        })
+    }
+}
+
+/// The integers should be server only, the tags should be send to the client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameType {
+    Nim = 1,
+    HeapNim = 2,
+}
+
+/// This implementation is important for database mapping.
+impl rusqlite::types::FromSql for GameType {
+    fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
+        use rusqlite::types::FromSqlError::{InvalidType, OutOfRange};
+        use rusqlite::types::ValueRef::Integer;
+        match value {
+            Integer(1) => Ok(GameType::Nim),
+            Integer(2) => Ok(GameType::HeapNim),
+            Integer(n) => Err(OutOfRange(n)),
+            _ => Err(InvalidType),
+        }
+    }
+}
+
+impl rusqlite::types::ToSql for GameType {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        use rusqlite::types::ToSqlOutput::Owned;
+        use rusqlite::types::Value::Integer;
+        Ok(Owned(Integer(*self as i64)))
+    }
+}
+
+/// The state of a game of any type.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GameState {
+    Nim(Nim),
+    HeapNim(HeapNim),
+}
+
+/// Positions with more legal actions only report how many there are.
+pub const MAX_LISTED_ACTIONS: usize = 1000;
+
+impl GameState {
+    /// The state before the first action. The rules must be valid.
+    pub fn new(rules: GameRules) -> Self {
+        match rules {
+            GameRules::Nim(rules) => GameState::Nim(Nim::new(rules)),
+            GameRules::HeapNim(rules) => GameState::HeapNim(HeapNim::new(rules)),
+        }
+    }
+
+    /// The legal actions of the current player. They are only listed if
+    /// there are at most `MAX_LISTED_ACTIONS` of them.
+    pub fn legal_actions(&self) -> LegalActions {
+        let count = match self {
+            GameState::Nim(state) => state.legal_action_count(),
+            GameState::HeapNim(state) => state.legal_action_count(),
+        };
+        let actions = if count <= MAX_LISTED_ACTIONS as u128 {
+            Some(match self {
+                GameState::Nim(state) => state.legal_actions().map(GameAction::Nim).collect(),
+                GameState::HeapNim(state) => {
+                    state.legal_actions().map(GameAction::HeapNim).collect()
+                }
+            })
+        } else {
+            None
+        };
+        let selectable = match self {
+            GameState::Nim(state) => state.selectable_tokens(),
+            GameState::HeapNim(state) => state.selectable_heaps(),
+        };
+
+        LegalActions {
+            count,
+            actions,
+            selectable,
+        }
+    }
+}
+
+/// An action for a game of any type. The game types use different fields,
+/// so the type of an action is known without a tag.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GameAction {
+    Nim(NimAction),
+    HeapNim(HeapNimAction),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ActionError {
+    /// The action belongs to another game type than the game.
+    WrongGameType,
+    Nim(NimError),
+    HeapNim(HeapNimError),
+}
+
+impl Game for GameState {
+    type Action = GameAction;
+    type Error = ActionError;
+
+    fn execute(&mut self, action: &GameAction) -> Result<(), ActionError> {
+        match (self, action) {
+            (GameState::Nim(state), GameAction::Nim(action)) => {
+                state.execute(action).map_err(ActionError::Nim)
+            }
+            (GameState::HeapNim(state), GameAction::HeapNim(action)) => {
+                state.execute(action).map_err(ActionError::HeapNim)
+            }
+            _ => Err(ActionError::WrongGameType),
+        }
+    }
+
+    fn current_player(&self) -> u8 {
+        match self {
+            GameState::Nim(state) => state.current_player(),
+            GameState::HeapNim(state) => state.current_player(),
+        }
+    }
+
+    fn winner(&self) -> Option<u8> {
+        match self {
+            GameState::Nim(state) => state.winner(),
+            GameState::HeapNim(state) => state.winner(),
+        }
+    }
+
+    fn player_count(&self) -> u8 {
+        match self {
+            GameState::Nim(state) => state.player_count(),
+            GameState::HeapNim(state) => state.player_count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn read_actions_of_each_type() {
+        // Stored actions of single heap games do not have a tag.
+        let action: GameAction = serde_json::from_str(r#"{"token_indices":[2,4]}"#).unwrap();
+        assert!(matches!(action, GameAction::Nim(_)));
+        let action: GameAction = serde_json::from_str(r#"{"heap":1,"count":2}"#).unwrap();
+        assert!(matches!(action, GameAction::HeapNim(_)));
+
+        let mut state = GameState::new(GameRules::HeapNim(HeapNimRules::default()));
+        let nim_action = serde_json::from_str(r#"{"token_indices":[0]}"#).unwrap();
+        assert_eq!(state.execute(&nim_action), Err(ActionError::WrongGameType));
+        state.execute(&action).unwrap();
+        assert_eq!(state.current_player(), 1);
+    }
+
+    #[test]
+    fn send_large_counts_as_strings() {
+        let state = GameState::new(GameRules::HeapNim(HeapNimRules {
+            heaps: vec![2, 1],
+            ..HeapNimRules::default()
+        }));
+        let json = serde_json::to_string(&state.legal_actions()).unwrap();
+        assert!(json.starts_with(r#"{"count":"3","#));
+
+        let large: LegalActions = serde_json::from_str(
+            r#"{"count":"340282366920938463463374607431768211455","actions":null,"selectable":[]}"#,
+        )
+        .unwrap();
+        assert_eq!(large.count, u128::MAX);
+    }
+
+    #[test]
+    fn store_rules_without_type() {
+        let rules = GameRules::HeapNim(HeapNimRules {
+            heaps: vec![1, 2],
+            ..HeapNimRules::default()
+        });
+        let json = rules.to_json().unwrap();
+        assert_eq!(
+            GameRules::from_json(rules.game_type(), &json).unwrap(),
+            rules
+        );
+        assert_eq!(
+            GameRules::from_json(GameType::Nim, "{}").unwrap(),
+            GameRules::default()
+        );
+    }
+}
@@ -1,4 +1,4 @@
-module Main exposing (main)
+port module Main exposing (main)
 
 import Browser
 import Browser.Navigation as Navigation
@@ -31,6 +31,16 @@ main =
         }
 
 
+{-| Asks the javascript side to open the websocket on /api/socket.
+-}
+port connectSocket : () -> Cmd msg
+
+
+{-| Messages pushed by the server over the websocket.
+-}
+port socketMessage : (Value -> msg) -> Sub msg
+
+
 type GameId
     = GameId Int
 
@@ -56,6 +66,9 @@ type alias Model =
 type alias Taco =
     { username : Maybe String
     , navKey : Navigation.Key
+
+    -- Sent with every request that changes something, see `post`.
+    , csrfToken : String
     }
 
 
@@ -76,7 +89,7 @@ type Msg
     | Logout
     | LoadGameList
     | GameListSuccess (List GameHeader)
-    | GameSuccess (Maybe GameHeader)
+    | GameLoaded GameId (WebData GameHeader)
     | FriendListSuccess (List UserInfo)
     | OpenSingleGame GameId
     | OpenDashboard
@@ -86,6 +99,7 @@ type Msg
     | ReloadFriends
     | GameReload GameId
     | UpdateMemberAssignment GameId GameMember
+    | PushReceived PushMessage
 
 
 init : Value -> Url -> Navigation.Key -> ( Model, Cmd Msg )
@@ -95,8 +109,12 @@ init flags url navKey =
             Decode.decodeValue decodeIdentity flags
                 |> Result.withDefault Nothing
 
+        csrfToken =
+            Decode.decodeValue (Decode.field "csrf_token" Decode.string) flags
+                |> Result.withDefault ""
+
         taco =
-            { username = identity, navKey = navKey }
+            { username = identity, navKey = navKey, csrfToken = csrfToken }
     in
     { taco = taco
     , usernameField = ""
@@ -109,6 +127,17 @@ init flags url navKey =
     , friends = RemoteData.NotAsked
     }
         |> initRoute url
+        |> Tuple.mapSecond (\cmd -> Cmd.batch [ cmd, initSocket identity ])
+
+
+initSocket : Maybe String -> Cmd Msg
+initSocket identity =
+    case identity of
+        Just _ ->
+            connectSocket ()
+
+        Nothing ->
+            Cmd.none
 
 
 initRoute : Url -> Model -> ( Model, Cmd Msg )
@@ -143,7 +172,15 @@ view model =
 
 subscriptions : Model -> Sub Msg
 subscriptions _ =
-    Sub.none
+    socketMessage
+        (\value ->
+            case Decode.decodeValue decodePushMessage value of
+                Ok message ->
+                    PushReceived message
+
+                Err _ ->
+                    NoOp
+        )
 
 
 onUrlRequest : Browser.UrlRequest -> Msg
@@ -169,7 +206,7 @@ update msg model =
             ( model, Cmd.none )
 
         LoginSuccess username ->
-            ( { model | taco = loginTaco username model.taco }, Cmd.none )
+            ( { model | taco = loginTaco username model.taco }, connectSocket () )
 
         HttpError error ->
             Debug.log "Http Error" (Debug.toString error) |> (\_ -> ( model, Cmd.none ))
@@ -184,10 +221,10 @@ update msg model =
             ( { model | passwordField = rawInput }, Cmd.none )
 
         TryLogin ->
-            ( model, login { username = model.usernameField, password = model.passwordField } )
+            ( model, login model.taco { username = model.usernameField, password = model.passwordField } )
 
         Logout ->
-            ( model, logout )
+            ( model, logout model.taco )
 
         LoadGameList ->
             ( { model | gameList = RemoteData.Loading }, loadGameList )
@@ -221,13 +258,8 @@ update msg model =
         GameCreated newGame ->
             ( appendReceivedGameList newGame model, Cmd.none )
 
-        GameSuccess game ->
-            case game of
-                Just newGame ->
-                    ( updateGameHeaderCache [ newGame ] model, Cmd.none )
-
-                Nothing ->
-                    ( model, Cmd.none )
+        GameLoaded (GameId gameId) game ->
+            ( { model | gameHeaderCache = Dict.insert gameId game model.gameHeaderCache }, Cmd.none )
 
         FriendListSuccess friends ->
             ( { model | friends = RemoteData.Success friends }, Cmd.none )
@@ -243,7 +275,7 @@ update msg model =
 
         SaveUpdateGameDescription gameId newDescription ->
             ( { model | changeGameDescription = Nothing }
-            , postSetupMessage gameId (SetDescription newDescription)
+            , postSetupMessage model.taco gameId (SetDescription newDescription)
             )
 
         CancelUpdateGameDescription ->
@@ -251,17 +283,95 @@ update msg model =
 
         UpdateMemberAssignment gameId gameMember ->
             ( model
-            , postSetupMessage gameId (UpdateMember gameMember)
+            , postSetupMessage model.taco gameId (UpdateMember gameMember)
             )
 
+        PushReceived message ->
+            applyPushMessage message model
+
+
+applyPushMessage : PushMessage -> Model -> ( Model, Cmd Msg )
+applyPushMessage message model =
+    case message of
+        GameCreatedPush game ->
+            ( appendReceivedGameList game model, Cmd.none )
+
+        DescriptionChanged gameId description ->
+            updateCachedGame gameId (\game -> { game | description = description }) model
+
+        MemberChanged gameId member ->
+            updateCachedGame gameId (\game -> { game | members = replaceMember member game.members }) model
+
+        GameChanged game ->
+            ( updateGameHeaderCache [ game ] model |> removeLeftGame game, Cmd.none )
+
+        StateChanged _ _ ->
+            -- The client does not show the game state yet. When the game
+            -- ends, the new status arrives as GameChanged.
+            ( model, Cmd.none )
+
+
+{-| Removes a game from the list once the user left it or declined the
+invitation, e.g. in another tab.
+-}
+removeLeftGame : GameHeader -> Model -> Model
+removeLeftGame game model =
+    let
+        isMember =
+            List.any (\member -> Just member.username == model.taco.username) game.members
+    in
+    if isMember then
+        model
+
+    else
+        { model | gameList = RemoteData.map (List.filter ((/=) (GameId game.id))) model.gameList }
+
+
+{-| Applies a change to a game in the cache. Games that we do not know yet
+are loaded from the server instead.
+-}
+updateCachedGame : GameId -> (GameHeader -> GameHeader) -> Model -> ( Model, Cmd Msg )
+updateCachedGame (GameId gameId) change model =
+    case Dict.get gameId model.gameHeaderCache of
+        Just (RemoteData.Success game) ->
+            ( updateGameHeaderCache [ change game ] model, Cmd.none )
+
+        _ ->
+            ( model, loadGame (GameId gameId) )
+
 
+replaceMember : GameMember -> List GameMember -> List GameMember
+replaceMember member members =
+    if List.any (\m -> m.id == member.id) members then
+        List.map
+            (\m ->
+                if m.id == member.id then
+                    member
+
+                else
+                    m
+            )
+            members
+
+    else
+        members ++ [ member ]
+
+
+{-| Adds a game to the front of the list. The creator of a game receives it
+twice, from the http response and from the websocket, so games that are
+already listed are only updated.
+-}
 appendReceivedGameList : GameHeader -> Model -> Model
 appendReceivedGameList game model =
     let
         newGameList =
             case model.gameList of
                 RemoteData.Success oldGameList ->
-                    GameId game.id :: oldGameList
+                    if List.member (GameId game.id) oldGameList then
+                        oldGameList
+
+                    else
+                        GameId game.id :: oldGameList
 
                 _ ->
                     [ GameId game.id ]
@@ -472,6 +582,9 @@ singleGame model (GameId id) =
                 RemoteData.Loading ->
                     Element.text "Loading game in progress..."
 
+                RemoteData.Failure (Http.BadStatus 404) ->
+                    Element.text "This game does not exist or you are not allowed to see it."
+
                 RemoteData.Failure _ ->
                     Element.text "Error while loading game."
 
@@ -553,7 +666,7 @@ inviteListEntryButton game userInfo role caption =
             { id = userInfo.id
             , role = role
             , username = userInfo.username
-            , accepted = False
+            , readyState = Invited
             }
     in
     Input.button []
@@ -728,9 +841,25 @@ decodeLogout =
     Decode.field "identity" (Decode.null ())
 
 
-login : LoginData -> Cmd Msg
-login data =
-    Http.post
+{-| Like `Http.post`, but the request carries the CSRF token. The server rejects
+requests that change something without it.
+-}
+post : Taco -> { url : String, body : Http.Body, expect : Http.Expect msg } -> Cmd msg
+post taco request =
+    Http.request
+        { method = "POST"
+        , headers = [ Http.header "X-CSRF-Token" taco.csrfToken ]
+        , url = request.url
+        , body = request.body
+        , expect = request.expect
+        , timeout = Nothing
+        , tracker = Nothing
+        }
+
+
+login : Taco -> LoginData -> Cmd Msg
+login taco data =
+    post taco
         { url = "/api/login"
         , body = Http.jsonBody (encodeLoginData data)
         , expect = Http.expectJson (defaultErrorHandler LoginSuccess) decodeUserName
@@ -747,10 +876,11 @@ defaultErrorHandler happyPath result =
             HttpError error
 
 
-logout : Cmd Msg
-logout =
-    Http.get
+logout : Taco -> Cmd Msg
+logout taco =
+    post taco
         { url = "/api/logout"
+        , body = Http.emptyBody
         , expect = Http.expectJson (defaultErrorHandler (\() -> LogoutSuccess)) decodeLogout
         }
 
@@ -774,7 +904,7 @@ type alias GameMember =
     { id : Int
     , username : String
     , role : MemberRole
-    , accepted : Bool
+    , readyState : ReadyState
     }
 
 
@@ -784,7 +914,7 @@ decodeGameMember =
         (Decode.field "id" Decode.int)
         (Decode.field "username" Decode.string)
         (Decode.field "role" decodeMemberRole)
-        (Decode.field "accepted" Decode.bool)
+        (Decode.field "ready_state" decodeReadyState)
 
 
 encodeGameMember : GameMember -> Value
@@ -793,10 +923,51 @@ encodeGameMember gameMember =
         [ ( "id", Encode.int <| gameMember.id )
         , ( "username", Encode.string <| gameMember.username )
         , ( "role", encodeMemberRole <| gameMember.role )
-        , ( "accepted", Encode.bool <| gameMember.accepted )
+        , ( "ready_state", encodeReadyState <| gameMember.readyState )
         ]
 
 
+{-| Tracks if a member accepted their invitation and is ready to play.
+-}
+type ReadyState
+    = Invited
+    | Accepted
+    | Ready
+
+
+decodeReadyState : Decoder ReadyState
+decodeReadyState =
+    Decode.string
+        |> Decode.andThen
+            (\string ->
+                case string of
+                    "Invited" ->
+                        Decode.succeed Invited
+
+                    "Accepted" ->
+                        Decode.succeed Accepted
+
+                    "Ready" ->
+                        Decode.succeed Ready
+
+                    _ ->
+                        Decode.fail ("Not valid pattern for decoder to ReadyState. Pattern: " ++ string)
+            )
+
+
+encodeReadyState : ReadyState -> Value
+encodeReadyState readyState =
+    case readyState of
+        Invited ->
+            Encode.string "Invited"
+
+        Accepted ->
+            Encode.string "Accepted"
+
+        Ready ->
+            Encode.string "Ready"
+
+
 decodeGameList : Decoder (List GameHeader)
 decodeGameList =
     Decode.list decodeGameHeader
@@ -814,7 +985,7 @@ loadGame : GameId -> Cmd Msg
 loadGame (GameId id) =
     Http.get
         { url = "/api/game/" ++ String.fromInt id
-        , expect = Http.expectJson (defaultErrorHandler GameSuccess) (Decode.maybe decodeGameHeader)
+        , expect = Http.expectJson (RemoteData.fromResult >> GameLoaded (GameId id)) decodeGameHeader
         }
 
 
@@ -831,7 +1002,7 @@ encodeGameCreate record =
 
 createGame : Model -> Cmd Msg
 createGame model =
-    Http.post
+    post model.taco
         { url = "/api/game/create"
         , body = Http.jsonBody (encodeGameCreate { description = model.newGameDescriptionField })
         , expect = Http.expectJson (defaultErrorHandler GameCreated) decodeGameHeader
@@ -876,18 +1047,50 @@ encodeSetupMessage message =
             Encode.object [ ( "UpdateMember", encodeGameMember <| member ) ]
 
 
-postSetupMessage : GameId -> SetupMessage -> Cmd Msg
-postSetupMessage (GameId gameId) message =
-    Http.post
+postSetupMessage : Taco -> GameId -> SetupMessage -> Cmd Msg
+postSetupMessage taco (GameId gameId) message =
+    post taco
         { url = "/api/game/" ++ String.fromInt gameId ++ "/setup"
         , body = Http.jsonBody (encodeSetupMessage message)
 
-        -- TODO: Here I will need a type like SetupMessage but for server
-        -- responses. This will make more sense one I get a Websocket running.
-        , expect = Http.expectJson (defaultErrorHandler (\() -> GameReload (GameId gameId))) (Decode.succeed ())
+        -- The changes are pushed to all members over the websocket.
+        , expect = Http.expectJson (defaultErrorHandler (\() -> NoOp)) (Decode.succeed ())
         }
 
 
+type PushMessage
+    = GameCreatedPush GameHeader
+    | DescriptionChanged GameId String
+    | MemberChanged GameId GameMember
+    | GameChanged GameHeader
+    | StateChanged GameId Value
+
+
+decodePushMessage : Decoder PushMessage
+decodePushMessage =
+    Decode.oneOf
+        [ Decode.field "GameCreated" decodeGameHeader
+            |> Decode.map GameCreatedPush
+        , Decode.field "DescriptionChanged"
+            (Decode.map2 DescriptionChanged
+                (Decode.field "game" (Decode.map GameId Decode.int))
+                (Decode.field "description" Decode.string)
+            )
+        , Decode.field "GameChanged" decodeGameHeader
+            |> Decode.map GameChanged
+        , Decode.field "MemberChanged"
+            (Decode.map2 MemberChanged
+                (Decode.field "game" (Decode.map GameId Decode.int))
+                (Decode.field "member" decodeGameMember)
+            )
+        , Decode.field "StateChanged"
+            (Decode.map2 StateChanged
+                (Decode.field "game" (Decode.map GameId Decode.int))
+                (Decode.field "state" Decode.value)
+            )
+        ]
+
+
 
 -------------------------------------------------------------------------------
 --------------------------- Taco helper functions -----------------------------